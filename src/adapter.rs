@@ -1,163 +1,2110 @@
-use std::collections::HashMap;
-
-use crate::ParsePolicyFailed;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use crate::audit::{self, AuditCall, AuditEvent, AuditOperation};
+use crate::batch::{BatchEntry, BatchWriter, RetryPolicy};
+use crate::builder::{self, DynamoDBAdapterBuilder};
+use crate::filter::{plan_reads, Expression, PolicyFilter, ReadRequest, SectionFilter};
+use crate::id::{IdStrategy, LengthPrefixedSha256};
+use crate::table::{self, AttributeNames, Billing, TableLayout, TableOptions};
+use crate::validity::{self, Validity, VALID_FROM, VALID_UNTIL};
+use crate::{AuditWriteFailed, DynamoDBAdapterError, InvalidConfig, MalformedItem, RuleTooLong};
 
 use async_trait::async_trait;
 use aws_sdk_dynamodb::{
-    model::{AttributeValue, DeleteRequest, PutRequest, ReturnValue, WriteRequest},
+    error::{TransactWriteItemsError, TransactWriteItemsErrorKind},
+    model::{AttributeValue, ConditionCheck, Delete, Put, ReturnValue, TransactWriteItem, Update},
+    types::SdkError,
     Client,
 };
-use casbin::{error::AdapterError, Adapter, Filter, Model, Result};
-
-use tokio_stream::StreamExt;
+use casbin::{Adapter, Filter, Model, Result};
+
+use futures_util::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+
+type Item = HashMap<String, AttributeValue>;
+/// A transaction action and the write undoing it.
+type Action = (TransactWriteItem, Option<BatchEntry>);
+/// A rule added or removed, with its policy type, as recorded in the audit table.
+type Change = (AuditOperation, String, Vec<String>);
+
+/// Id of the item holding the policy generation published by the last snapshot save.
+const GENERATION_ID: &str = "__casbin_generation__";
+const META_PTYPE: &str = "__meta__";
+/// Attributes of the generation item holding the claim of the snapshot save in progress, see
+/// `DynamoDBAdapter::claim_generation`.
+const PENDING: &str = "pending";
+const PENDING_SINCE: &str = "pendingSince";
+/// Last generation claimed by a snapshot save, so that no two saves tag items with the same one.
+const CLAIMED: &str = "claimed";
+/// Seconds after which the claim of a snapshot save that neither published nor gave up, e.g.
+/// because its process died, can be taken over.
+const CLAIM_TIMEOUT_SECONDS: i64 = 900;
+
+/// Maximum number of actions in a single `TransactWriteItems` call.
+pub(crate) const MAX_TRANSACT_ITEMS: usize = 100;
+/// Number of fields of the stored rule, so empty fields (which are not written) keep their
+/// position on load. Items written without it are read back with their present fields only.
+const FIELD_COUNT: &str = "fieldCount";
+/// Largest number of fields of a stored rule, so that a corrupt `fieldCount` can not make a load
+/// allocate an arbitrarily long rule.
+pub(crate) const MAX_FIELD_COUNT: usize = 1024;
+/// Tenant owning the item, written when the adapter has a namespace.
+const NAMESPACE: &str = "namespace";
+/// Attributes written by the adapter next to the id, policy type and rule fields.
+pub(crate) const RESERVED_ATTRIBUTES: [&str; 9] = [
+    "gen",
+    "retired",
+    PENDING,
+    PENDING_SINCE,
+    CLAIMED,
+    FIELD_COUNT,
+    NAMESPACE,
+    VALID_FROM,
+    VALID_UNTIL,
+];
+
+/// Outcome of `DynamoDBAdapter::sync_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DynamoDBAdapter {
-    client: Client,
-    table_name: String,
-    is_filtered: bool,
+    pub(crate) client: Client,
+    pub(crate) table_name: String,
+    pub(crate) is_filtered: bool,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) indexes: HashMap<usize, String>,
+    pub(crate) layout: TableLayout,
+    pub(crate) max_transact_items: usize,
+    pub(crate) max_fields: Option<usize>,
+    pub(crate) id_strategy: Arc<dyn IdStrategy>,
+    pub(crate) attributes: AttributeNames,
+    pub(crate) consistent_read: bool,
+    pub(crate) scan_segments: u32,
+    pub(crate) batch_size: usize,
+    pub(crate) write_concurrency: usize,
+    pub(crate) namespace: Option<String>,
+    pub(crate) transactional: bool,
+    pub(crate) audit_table: Option<String>,
+    pub(crate) audit_required: bool,
+    pub(crate) max_unrecorded_audit: usize,
+    pub(crate) ttl_attribute: Option<String>,
+    /// Audit writes that failed, see `unrecorded_audit_events`.
+    pub(crate) unrecorded_audit: Arc<Mutex<Vec<BatchEntry>>>,
 }
 
-impl DynamoDBAdapter {
-    pub fn new(client: &Client, table_name: &str) -> Result<Self> {
-        Ok(Self {
-            client: client.clone(),
-            table_name: table_name.to_string(),
-            is_filtered: false,
-        })
+impl DynamoDBAdapter {
+    pub fn new(client: &Client, table_name: &str) -> Result<Self> {
+        Self::builder()
+            .client(client)
+            .table_name(table_name)
+            .build()
+    }
+
+    /// Starts configuring an adapter, see [`DynamoDBAdapterBuilder`].
+    pub fn builder() -> DynamoDBAdapterBuilder {
+        DynamoDBAdapterBuilder::default()
+    }
+
+    /// Sets how unprocessed batch writes are retried before giving up.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Result<Self> {
+        self.retry = retry;
+        self.validated()
+    }
+
+    /// Registers a global secondary index whose partition key is the rule field `v{field_index}`
+    /// and whose sort key is the policy type.
+    ///
+    /// Filtered loads that pin this field `Query` the index for each policy type instead of
+    /// scanning the table.
+    pub fn with_index(mut self, field_index: usize, index_name: &str) -> Result<Self> {
+        self.indexes.insert(field_index, index_name.to_string());
+        self.validated()
+    }
+
+    /// Selects the key schema of the table, see [`TableLayout`].
+    pub fn with_layout(mut self, layout: TableLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Sets the largest policy change `save_policy` applies with a single `TransactWriteItems`.
+    ///
+    /// Larger changes are published as a new snapshot generation. Defaults to the service limit
+    /// of 100; DynamoDB Local only accepts 25.
+    pub fn with_max_transaction_items(mut self, max_transact_items: usize) -> Result<Self> {
+        self.max_transact_items = max_transact_items;
+        self.validated()
+    }
+
+    /// Limits the number of fields of a rule; longer rules are rejected with
+    /// [`RuleTooLong`](crate::RuleTooLong). Rules are limited to 1024 fields by default.
+    pub fn with_max_fields(mut self, max_fields: usize) -> Result<Self> {
+        self.max_fields = Some(max_fields);
+        self.validated()
+    }
+
+    /// Makes `add_policies` and `remove_policies` all-or-nothing at any size.
+    ///
+    /// Rules are written with `TransactWriteItems` in chunks of `max_transaction_items`. If a
+    /// chunk fails, the chunks already committed are undone with compensating writes, which
+    /// other readers may briefly observe. `remove_policies` then also returns `Ok(false)` when
+    /// one of the rules is not stored.
+    pub fn with_transactional_writes(mut self, transactional: bool) -> Self {
+        self.transactional = transactional;
+        self
+    }
+
+    /// Makes every read strongly consistent, see `load_policy_consistent`.
+    pub fn with_consistent_read(mut self, consistent_read: bool) -> Self {
+        self.consistent_read = consistent_read;
+        self
+    }
+
+    /// Sets how item ids are derived from rules, see [`IdStrategy`]. Defaults to
+    /// [`LegacyMd5`](crate::LegacyMd5).
+    ///
+    /// Items already stored keep their ids until `migrate_item_ids` rewrites them.
+    pub fn with_id_strategy<S: IdStrategy + 'static>(mut self, id_strategy: S) -> Self {
+        self.id_strategy = Arc::new(id_strategy);
+        self
+    }
+
+    /// Records every rule added or removed in the audit table `table_name`, see
+    /// `create_audit_table_if_not_exists`.
+    pub fn with_audit_table(mut self, table_name: &str) -> Result<Self> {
+        self.audit_table = Some(table_name.to_string());
+        self.validated()
+    }
+
+    /// Fails every call whose audit events could not be written with
+    /// [`AuditWriteFailed`](crate::AuditWriteFailed), although its change is stored.
+    ///
+    /// By default only calls whose events no longer fit in the buffer of unrecorded events fail.
+    pub fn with_audit_required(mut self, required: bool) -> Self {
+        self.audit_required = required;
+        self
+    }
+
+    /// Limits the audit events kept in memory for `flush_audit_events`. Defaults to 10000.
+    pub fn with_max_unrecorded_audit_events(mut self, max_events: usize) -> Self {
+        self.max_unrecorded_audit = max_events;
+        self
+    }
+
+    /// Writes the end of a rule's validity window to `attribute` as well, so that DynamoDB's time
+    /// to live deletes expired rules. Enable it on the table with `TableOptions::ttl_attribute`.
+    pub fn with_ttl_attribute(mut self, attribute: &str) -> Result<Self> {
+        self.ttl_attribute = Some(attribute.to_string());
+        self.validated()
+    }
+
+    /// Checks the configuration after a setter changed it, like `DynamoDBAdapterBuilder::build`.
+    fn validated(self) -> Result<Self> {
+        builder::validate(&self)?;
+
+        Ok(self)
+    }
+
+    /// Creates the table with the key schema of the selected layout and the registered indexes,
+    /// waiting until it is `ACTIVE`.
+    ///
+    /// If the table already exists, it is waited for as well and its key schema and indexes are
+    /// checked against the adapter configuration, returning
+    /// [`TableSetupFailed`](crate::TableSetupFailed) on mismatch. `options.ttl_attribute` is
+    /// enabled on either table, which fails if time to live is on another attribute.
+    pub async fn create_table_if_not_exists(&self, options: &TableOptions) -> Result<()> {
+        table::create_table_if_not_exists(
+            &self.client,
+            &self.table_name,
+            self.layout,
+            &self.attributes,
+            &self.indexes,
+            options,
+        )
+        .await
+    }
+
+    /// Creates the audit table set with `with_audit_table`, keyed by rule and time with a
+    /// `subject-index` on the first rule field, and waits until it is `ACTIVE`.
+    pub async fn create_audit_table_if_not_exists(&self, billing: Billing) -> Result<()> {
+        audit::create_table_if_not_exists(&self.client, self.audit_table()?, billing).await
+    }
+
+    /// Audit events of a rule, oldest first.
+    pub async fn audit_history_for_rule(
+        &self,
+        ptype: &str,
+        rule: &[String],
+    ) -> Result<Vec<AuditEvent>> {
+        let key = self.audit_rule_key(ptype, rule);
+        audit::query_events(
+            &self.client,
+            self.audit_table()?,
+            None,
+            audit::RULE_KEY,
+            &key,
+        )
+        .await
+    }
+
+    /// Audit events of the rules whose first field is `subject`, across policy types and
+    /// oldest first.
+    pub async fn audit_history_for_subject(&self, subject: &str) -> Result<Vec<AuditEvent>> {
+        audit::query_events(
+            &self.client,
+            self.audit_table()?,
+            Some(audit::SUBJECT_INDEX),
+            audit::SUBJECT,
+            &self.namespaced(subject),
+        )
+        .await
+    }
+
+    fn audit_table(&self) -> Result<&str> {
+        self.audit_table
+            .as_deref()
+            .ok_or_else(|| InvalidConfig("no audit table is set".to_string()).into())
+    }
+
+    /// Audit key of a rule, independent of the id strategy so that history survives
+    /// `migrate_item_ids`.
+    fn audit_rule_key(&self, ptype: &str, rule: &[String]) -> String {
+        self.namespaced(&LengthPrefixedSha256.item_id(ptype, rule))
+    }
+
+    /// Audit events of changes that are stored but whose audit write failed, oldest first.
+    ///
+    /// The events are kept in memory, for this adapter and its clones, until
+    /// `flush_audit_events` writes them, and are lost if the process exits first.
+    pub fn unrecorded_audit_events(&self) -> Result<Vec<AuditEvent>> {
+        let audit_table = self.audit_table()?;
+        self.unrecorded()
+            .iter()
+            .filter_map(|entry| entry.request.put_request()?.item())
+            .map(|item| audit::item_to_event(audit_table, item))
+            .collect()
+    }
+
+    /// Writes the events of `unrecorded_audit_events` again; those still failing are kept.
+    pub async fn flush_audit_events(&self) -> Result<()> {
+        let audit_table = self.audit_table()?;
+        let entries = std::mem::take(&mut *self.unrecorded());
+        if let Err(e) = self.audit_writer(audit_table).write(entries.clone()).await {
+            self.unrecorded().splice(0..0, entries);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn unrecorded(&self) -> MutexGuard<'_, Vec<BatchEntry>> {
+        self.unrecorded_audit
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn audit_writer<'a>(&'a self, audit_table: &'a str) -> BatchWriter<'a> {
+        BatchWriter::new(&self.client, audit_table, &self.retry, self.batch_size)
+    }
+
+    /// Records the changes of a call of the adapter method `method`, see `record_call`.
+    async fn record(&self, method: &str, changes: Vec<Change>) -> Result<()> {
+        self.record_call(&mut AuditCall::new(method), changes).await
+    }
+
+    /// Writes one audit event per change made by `call`, with its actor and correlation id.
+    ///
+    /// The changes are already stored, so a failed write keeps the events for
+    /// `flush_audit_events` while they fit in `max_unrecorded_audit`. It only fails the call
+    /// when they do not, or when audit writes are required.
+    async fn record_call(&self, call: &mut AuditCall, changes: Vec<Change>) -> Result<()> {
+        let audit_table = match &self.audit_table {
+            Some(audit_table) if !changes.is_empty() => audit_table,
+            _ => return Ok(()),
+        };
+
+        let timestamp = audit::now_millis();
+        let mut entries = Vec::new();
+        for (operation, ptype, rule) in &changes {
+            let subject = rule
+                .first()
+                .filter(|v| !v.is_empty())
+                .map(|v| self.namespaced(v));
+            let item = audit::event_item(
+                self.audit_rule_key(ptype, rule),
+                call.next_event_key(timestamp),
+                subject,
+                *operation,
+                &call.method,
+                ptype,
+                rule,
+                timestamp,
+                &call.context,
+                &call.correlation_id,
+            );
+            entries.push(BatchEntry::put(ptype, rule, item));
+        }
+
+        if let Err(source) = self.audit_writer(audit_table).write(entries.clone()).await {
+            let events = entries.len();
+            let kept = {
+                let mut unrecorded = self.unrecorded();
+                let kept = unrecorded.len() + events <= self.max_unrecorded_audit;
+                if kept {
+                    unrecorded.extend(entries);
+                }
+                kept
+            };
+            if self.audit_required || !kept {
+                return Err(AuditWriteFailed {
+                    audit_table: audit_table.clone(),
+                    events,
+                    kept,
+                    source,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "watcher")]
+    pub(crate) fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    /// ARN of the table's latest stream, if streams are enabled.
+    #[cfg(feature = "watcher")]
+    pub(crate) async fn stream_arn(&self) -> Result<Option<String>> {
+        let res = self
+            .client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(|e| {
+                DynamoDBAdapterError::from_sdk(&self.table_name, "DescribeTable", None, e)
+            })?;
+
+        Ok(res
+            .table()
+            .and_then(|t| t.latest_stream_arn())
+            .map(str::to_string))
+    }
+
+    fn batch_writer(&self) -> BatchWriter<'_> {
+        BatchWriter::new(&self.client, &self.table_name, &self.retry, self.batch_size)
+    }
+
+    fn item_key(
+        &self,
+        item: &HashMap<String, AttributeValue>,
+    ) -> Option<HashMap<String, AttributeValue>> {
+        let attributes = &self.attributes;
+        let mut key = HashMap::new();
+        key.insert(attributes.id.clone(), item.get(&attributes.id)?.to_owned());
+        if self.layout == TableLayout::PType {
+            key.insert(
+                attributes.ptype.clone(),
+                item.get(&attributes.ptype)?.to_owned(),
+            );
+        }
+
+        Some(key)
+    }
+
+    fn rule_key(&self, ptype: &str, rule: &[String]) -> Result<HashMap<String, AttributeValue>> {
+        let mut key = HashMap::new();
+        key.insert(
+            self.attributes.id.clone(),
+            AttributeValue::S(self.get_item_id(ptype, rule)?),
+        );
+        if self.layout == TableLayout::PType {
+            key.insert(
+                self.attributes.ptype.clone(),
+                AttributeValue::S(ptype.to_string()),
+            );
+        }
+
+        Ok(key)
+    }
+
+    fn generation_key(&self) -> HashMap<String, AttributeValue> {
+        let mut key = HashMap::new();
+        key.insert(
+            self.attributes.id.clone(),
+            AttributeValue::S(self.generation_id()),
+        );
+        if self.layout == TableLayout::PType {
+            key.insert(
+                self.attributes.ptype.clone(),
+                AttributeValue::S(META_PTYPE.to_string()),
+            );
+        }
+
+        key
+    }
+
+    /// Returns the generation published by the last snapshot save, `0` if there was none.
+    ///
+    /// Conditional writes need it to tell stored rules from the items of a snapshot save, so
+    /// every add and update first reads the generation item with a strongly consistent `GetItem`.
+    pub(crate) async fn current_generation(&self) -> Result<i64> {
+        let res = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.generation_key()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| DynamoDBAdapterError::from_sdk(&self.table_name, "GetItem", None, e))?;
+
+        Ok(res
+            .item()
+            .and_then(|item| number_attribute(item, "gen"))
+            .unwrap_or(0))
+    }
+
+    /// Filter condition hiding the generation item and rules not visible at `generation`.
+    ///
+    /// Rules written by a snapshot save carry the `gen` it claimed and rules it replaced carry
+    /// that generation as `retired`. Claimed generations are never reused, so the items of a
+    /// save that did not publish its generation stay hidden, and those of the published one
+    /// only lose their mark when the next snapshot save rewrites them.
+    fn visibility_condition(&self, expr: &mut Expression, generation: i64) -> String {
+        let id = expr.name(&self.attributes.id);
+        let meta = expr.value(&self.generation_id());
+        let gen = expr.name("gen");
+        let retired = expr.name("retired");
+        let current = expr.number(generation);
+
+        format!(
+            "{id} <> {meta} AND (attribute_not_exists({gen}) OR {gen} = {current}) AND (attribute_not_exists({retired}) OR {retired} <> {current})",
+            id = id,
+            meta = meta,
+            gen = gen,
+            retired = retired,
+            current = current,
+        )
+    }
+
+    /// Excludes rules outside their validity window at `now`, even before time to live deletes
+    /// them.
+    fn active_condition(&self, expr: &mut Expression, now: i64) -> String {
+        let from = expr.name(VALID_FROM);
+        let until = expr.name(VALID_UNTIL);
+        let now = expr.number(now);
+
+        format!(
+            "(attribute_not_exists({from}) OR {from} <= {now}) AND (attribute_not_exists({until}) OR {until} > {now})",
+            from = from,
+            until = until,
+            now = now,
+        )
+    }
+
+    fn is_active(&self, item: &HashMap<String, AttributeValue>, now: i64) -> bool {
+        number_attribute(item, VALID_FROM).is_none_or(|from| from <= now)
+            && number_attribute(item, VALID_UNTIL).is_none_or(|until| until > now)
+    }
+
+    fn set_validity(&self, item: &mut HashMap<String, AttributeValue>, validity: &Validity) {
+        if let Some(from) = validity.valid_from_seconds() {
+            item.insert(VALID_FROM.to_string(), AttributeValue::N(from.to_string()));
+        }
+        if let Some(until) = validity.valid_until_seconds() {
+            item.insert(
+                VALID_UNTIL.to_string(),
+                AttributeValue::N(until.to_string()),
+            );
+            if let Some(ttl_attribute) = &self.ttl_attribute {
+                item.insert(ttl_attribute.clone(), AttributeValue::N(until.to_string()));
+            }
+        }
+    }
+
+    pub(crate) fn item_id<'i>(&self, item: &'i HashMap<String, AttributeValue>) -> &'i str {
+        item.get(&self.attributes.id)
+            .and_then(|att| att.as_s().ok())
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    /// Client-side counterpart of `visibility_condition`.
+    pub(crate) fn is_visible(
+        &self,
+        item: &HashMap<String, AttributeValue>,
+        generation: i64,
+    ) -> bool {
+        !self.is_generation_item(item)
+            && number_attribute(item, "gen").is_none_or(|g| g == generation)
+            && number_attribute(item, "retired").is_none_or(|r| r != generation)
+    }
+
+    /// Whether a load at `generation` and `now` returns the rule stored in `item`.
+    #[cfg(feature = "watcher")]
+    pub(crate) fn is_loaded(
+        &self,
+        item: &HashMap<String, AttributeValue>,
+        generation: i64,
+        now: i64,
+    ) -> bool {
+        self.is_visible(item, generation) && self.is_active(item, now)
+    }
+
+    fn get_item_id(&self, ptype: &str, rule: &[String]) -> Result<String> {
+        self.check_rule_len(ptype, rule)?;
+
+        Ok(self.namespaced(&self.id_strategy.item_id(ptype, rule)))
+    }
+
+    /// Prefixes `id` with the namespace, so tenants sharing a table never share a key.
+    ///
+    /// Without a namespace, ids containing `#` are prefixed with an empty one, so that no id
+    /// written without a namespace reads as one written with it.
+    fn namespaced(&self, id: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}#{}", namespace, id),
+            None if id.contains('#') => format!("#{}", id),
+            None => id.to_string(),
+        }
+    }
+
+    fn generation_id(&self) -> String {
+        self.namespaced(GENERATION_ID)
+    }
+
+    pub(crate) fn is_generation_item(&self, item: &HashMap<String, AttributeValue>) -> bool {
+        self.item_id(item) == self.generation_id()
+    }
+
+    /// Whether `item` belongs to the adapter's namespace; items without one belong to adapters
+    /// without a namespace.
+    #[cfg(feature = "watcher")]
+    pub(crate) fn in_namespace(&self, item: &HashMap<String, AttributeValue>) -> bool {
+        let namespace = item.get(NAMESPACE).and_then(|att| att.as_s().ok());
+        namespace == self.namespace.as_ref()
+    }
+
+    /// Condition restricting a read to the items of the adapter's namespace.
+    fn namespace_condition(&self, expr: &mut Expression) -> String {
+        let name = expr.name(NAMESPACE);
+        match &self.namespace {
+            Some(namespace) => format!("{} = {}", name, expr.value(namespace)),
+            None => format!("attribute_not_exists({})", name),
+        }
+    }
+
+    /// Condition of a write that the item at its key, if there is one, belongs to the
+    /// adapter's namespace.
+    fn owned_condition(&self, expr: &mut Expression) -> String {
+        format!(
+            "(attribute_not_exists({}) OR {})",
+            expr.name(&self.attributes.id),
+            self.namespace_condition(expr)
+        )
+    }
+
+    /// Condition of a write that the item at its key exists and belongs to the adapter's
+    /// namespace.
+    fn stored_condition(&self, expr: &mut Expression) -> String {
+        format!(
+            "attribute_exists({}) AND {}",
+            expr.name(&self.attributes.id),
+            self.namespace_condition(expr)
+        )
+    }
+
+    fn set_namespace(&self, item: &mut HashMap<String, AttributeValue>) {
+        if let Some(namespace) = &self.namespace {
+            item.insert(
+                NAMESPACE.to_string(),
+                AttributeValue::S(namespace.to_string()),
+            );
+        }
+    }
+
+    fn check_rule_len(&self, ptype: &str, rule: &[String]) -> Result<()> {
+        let max_fields = self.max_fields.unwrap_or(MAX_FIELD_COUNT);
+        if rule.len() > max_fields {
+            return Err(RuleTooLong {
+                ptype: ptype.to_string(),
+                rule: rule.to_vec(),
+                max_fields,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn policy_to_item(
+        &self,
+        ptype: &str,
+        rule: &[String],
+    ) -> Result<HashMap<String, AttributeValue>> {
+        let mut item: HashMap<String, AttributeValue> = HashMap::new();
+
+        item.insert(
+            self.attributes.ptype.clone(),
+            AttributeValue::S(ptype.to_string()),
+        );
+
+        for (i, v) in rule.iter().enumerate() {
+            if !v.is_empty() {
+                item.insert(self.attributes.field(i), AttributeValue::S(v.to_string()));
+            }
+        }
+
+        item.insert(
+            FIELD_COUNT.to_string(),
+            AttributeValue::N(rule.len().to_string()),
+        );
+
+        let id = self.get_item_id(ptype, rule)?;
+        item.insert(self.attributes.id.clone(), AttributeValue::S(id));
+        self.set_namespace(&mut item);
+
+        Ok(item)
+    }
+
+    pub(crate) fn item_to_policy(
+        &self,
+        item: &HashMap<String, AttributeValue>,
+    ) -> Result<(String, Vec<String>)> {
+        let mut ptype = "".to_string();
+
+        if let Some(att) = item.get(&self.attributes.ptype) {
+            if let Ok(v) = att.as_s() {
+                ptype = v.to_owned();
+            }
+        }
+
+        let mut fields: Vec<(usize, String)> = Vec::new();
+        for (key, att) in item {
+            let index = match self.attributes.field_index(key) {
+                Some(index) => index,
+                None => continue,
+            };
+            if let Ok(v) = att.as_s() {
+                fields.push((index, v.to_owned()));
+            }
+        }
+        fields.sort();
+
+        let rule = match item.get(FIELD_COUNT) {
+            Some(att) => {
+                let count = att
+                    .as_n()
+                    .ok()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .filter(|count| *count <= MAX_FIELD_COUNT)
+                    .filter(|count| fields.last().is_none_or(|(index, _)| index < count))
+                    .ok_or_else(|| MalformedItem {
+                        table_name: self.table_name.clone(),
+                        item_id: self.item_id(item).to_string(),
+                        reason: format!("{} is out of range", FIELD_COUNT),
+                    })?;
+
+                let mut rule = vec![String::new(); count];
+                for (index, v) in fields {
+                    rule[index] = v;
+                }
+                rule
+            }
+            None => fields.into_iter().map(|(_, v)| v).collect(),
+        };
+
+        Ok((ptype, rule))
+    }
+
+    /// Reads a loaded item as a rule, rejecting items without a policy type or rule fields.
+    fn loaded_policy(
+        &self,
+        item: &HashMap<String, AttributeValue>,
+    ) -> Result<(String, Vec<String>)> {
+        let (ptype, rule) = self.item_to_policy(item)?;
+        if ptype.is_empty() || rule.is_empty() {
+            return Err(MalformedItem {
+                table_name: self.table_name.clone(),
+                item_id: self.item_id(item).to_string(),
+                reason: "missing policy type or rule fields".to_string(),
+            }
+            .into());
+        }
+
+        Ok((ptype, rule))
+    }
+
+    /// Rewrites every stored item whose id was derived with another strategy under the id of the
+    /// configured [`IdStrategy`], returning the number of items moved.
+    ///
+    /// All items are copied before any old one is deleted, so an interrupted migration leaves
+    /// duplicates rather than losing rules and can simply be run again.
+    pub async fn migrate_item_ids(&self) -> Result<usize> {
+        let items = self.read_items(ReadRequest::default()).await?;
+
+        let mut puts = Vec::new();
+        let mut deletes = Vec::new();
+        for item in items {
+            if self.is_generation_item(&item) {
+                continue;
+            }
+
+            let (ptype, rule) = self.item_to_policy(&item)?;
+            let id = self.get_item_id(&ptype, &rule)?;
+            if id == self.item_id(&item) {
+                continue;
+            }
+
+            if let Some(key) = self.item_key(&item) {
+                let mut moved = item.clone();
+                moved.insert(self.attributes.id.clone(), AttributeValue::S(id));
+                puts.push(BatchEntry::put(&ptype, &rule, moved));
+                deletes.push(BatchEntry::delete(&ptype, &rule, key));
+            }
+        }
+
+        let migrated = puts.len();
+        self.batch_writer().write(puts).await?;
+        self.batch_writer().write(deletes).await?;
+
+        Ok(migrated)
+    }
+
+    /// Loads the policy with strongly consistent reads, so it includes every write acknowledged
+    /// before the call.
+    ///
+    /// Filtered reads skip the registered global secondary indexes, which only support eventually
+    /// consistent reads, and consistent reads consume twice the read capacity.
+    pub async fn load_policy_consistent(&self, m: &mut dyn Model) -> Result<()> {
+        self.load_filtered_policy_into_model(m, &PolicyFilter::new(), true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Consistent counterpart of `load_filtered_policy`, see `load_policy_consistent`.
+    pub async fn load_filtered_policy_consistent<F: Into<PolicyFilter>>(
+        &mut self,
+        m: &mut dyn Model,
+        f: F,
+    ) -> Result<()> {
+        self.is_filtered = self
+            .load_filtered_policy_into_model(m, &f.into(), true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Loads the rules matching a filter set per policy type, so that `g` and `g2` (or `p` and
+    /// `p2`) can be filtered differently.
+    pub async fn load_filtered_policy_by_ptype(
+        &mut self,
+        m: &mut dyn Model,
+        filter: &PolicyFilter,
+    ) -> Result<()> {
+        self.is_filtered = self
+            .load_filtered_policy_into_model(m, filter, self.consistent_read)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Adds the rules that are not stored yet, with one conditional write per rule, and returns
+    /// them.
+    ///
+    /// Unlike `add_policies` with transactional writes, this never fails because some rules
+    /// already exist.
+    pub async fn add_new_policies(
+        &self,
+        ptype: &str,
+        rules: Vec<Vec<String>>,
+    ) -> Result<Vec<Vec<String>>> {
+        let added = self.put_new_policies(ptype, rules).await?;
+        self.record(
+            "add_new_policies",
+            changes(AuditOperation::Add, ptype, &added),
+        )
+        .await?;
+
+        Ok(added)
+    }
+
+    /// Adds a rule that is only loaded until `expires_at`, see `add_policy_with_validity`.
+    pub async fn add_policy_with_expiry(
+        &self,
+        ptype: &str,
+        rule: Vec<String>,
+        expires_at: SystemTime,
+    ) -> Result<bool> {
+        self.add_policy_with_validity(ptype, rule, Validity::until(expires_at))
+            .await
+    }
+
+    /// Adds a rule that loads only return within `validity`, or `Ok(false)` if it is already
+    /// stored and has not expired.
+    ///
+    /// The window is stored with the rule, and written to the time to live attribute set with
+    /// `with_ttl_attribute`. `save_policy` keeps the window of the rules the model has, so a rule
+    /// loaded before it expired is not stored again without one, and leaves rules that are not
+    /// yet active or expired alone.
+    pub async fn add_policy_with_validity(
+        &self,
+        ptype: &str,
+        rule: Vec<String>,
+        validity: Validity,
+    ) -> Result<bool> {
+        if let (Some(from), Some(until)) = (validity.valid_from, validity.valid_until) {
+            if from >= until {
+                return Err(InvalidConfig(
+                    "a validity window must start before it ends".to_string(),
+                )
+                .into());
+            }
+        }
+
+        let generation = self.current_generation().await?;
+        if !self
+            .put_if_absent(ptype, &rule, generation, &validity)
+            .await?
+        {
+            return Ok(false);
+        }
+
+        self.record(
+            "add_policy_with_validity",
+            vec![(AuditOperation::Add, ptype.to_string(), rule)],
+        )
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Returns the earliest time after now at which a stored rule becomes active or expires, so
+    /// that enforcers can schedule a reload.
+    ///
+    /// This scans the table for rules with a validity window.
+    pub async fn next_validity_change(&self) -> Result<Option<SystemTime>> {
+        let now = validity::now_seconds();
+        let generation = self.current_generation().await?;
+
+        let mut read = ReadRequest::default();
+        let from = read.expression.name(VALID_FROM);
+        let until = read.expression.name(VALID_UNTIL);
+        let after = read.expression.number(now);
+        read.and_filter(format!("{} > {} OR {} > {}", from, after, until, after));
+        let visible = self.visibility_condition(&mut read.expression, generation);
+        read.and_filter(visible);
+
+        let mut next: Option<i64> = None;
+        let mut pages = self.read_pages(read, self.consistent_read);
+        while let Some(page) = pages.try_next().await? {
+            for item in &page {
+                for attribute in [VALID_FROM, VALID_UNTIL] {
+                    if let Some(t) = number_attribute(item, attribute).filter(|t| *t > now) {
+                        next = Some(next.map_or(t, |n| n.min(t)));
+                    }
+                }
+            }
+        }
+
+        Ok(next.map(validity::from_epoch_seconds))
+    }
+
+    async fn put_new_policies(
+        &self,
+        ptype: &str,
+        rules: Vec<Vec<String>>,
+    ) -> Result<Vec<Vec<String>>> {
+        let generation = self.current_generation().await?;
+
+        let results: Vec<(Vec<String>, bool)> = stream::iter(rules)
+            .map(|rule| async move {
+                let added = self
+                    .put_if_absent(ptype, &rule, generation, &Validity::default())
+                    .await?;
+                Ok::<_, casbin::Error>((rule, added))
+            })
+            .buffer_unordered(self.write_concurrency)
+            .try_collect()
+            .await?;
+
+        Ok(results
+            .into_iter()
+            .filter(|(_, added)| *added)
+            .map(|(rule, _)| rule)
+            .collect())
+    }
+
+    /// Drops the rules stored under the same item id as an earlier one, keeping their order.
+    fn unique_rules(&self, ptype: &str, rules: Vec<Vec<String>>) -> Result<Vec<Vec<String>>> {
+        let mut ids = HashSet::new();
+        let mut unique = Vec::new();
+        for rule in rules {
+            if ids.insert(self.get_item_id(ptype, &rule)?) {
+                unique.push(rule);
+            }
+        }
+
+        Ok(unique)
+    }
+
+    /// Condition under which a rule's item is stored, the complement of `absent_condition`: it
+    /// is visible at `generation` and has not expired. Rules that are not active yet can still
+    /// be revoked.
+    fn present_condition(&self, expr: &mut Expression, generation: i64) -> String {
+        let stored = self.stored_condition(expr);
+        let visible = self.visibility_condition(expr, generation);
+        let until = expr.name(VALID_UNTIL);
+
+        format!(
+            "{stored} AND {visible} AND (attribute_not_exists({until}) OR {until} > {now})",
+            stored = stored,
+            visible = visible,
+            until = until,
+            now = expr.number(validity::now_seconds()),
+        )
+    }
+
+    /// Deletes the rule's item, returning whether it was stored at `generation`.
+    ///
+    /// Items that are not visible or have expired are left to the next snapshot save and to time
+    /// to live.
+    async fn delete_if_present(
+        &self,
+        ptype: &str,
+        rule: &[String],
+        generation: i64,
+    ) -> Result<bool> {
+        let key = self.rule_key(ptype, rule)?;
+        let mut expr = Expression::default();
+        let condition = self.present_condition(&mut expr, generation);
+        let (names, values) = expr.into_parts();
+
+        let res = self
+            .client
+            .delete_item()
+            .table_name(&self.table_name)
+            .set_key(Some(key))
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(DynamoDBAdapterError::from_sdk(
+                &self.table_name,
+                "DeleteItem",
+                Some((ptype, rule)),
+                e,
+            )
+            .into()),
+        }
+    }
+
+    /// Deletes the rules with one `DeleteItem` per rule and returns those that were stored.
+    async fn delete_present(
+        &self,
+        ptype: &str,
+        rules: Vec<Vec<String>>,
+    ) -> Result<Vec<Vec<String>>> {
+        let generation = self.current_generation().await?;
+        let results: Vec<(Vec<String>, bool)> = stream::iter(rules)
+            .map(|rule| async move {
+                let removed = self.delete_if_present(ptype, &rule, generation).await?;
+                Ok::<_, casbin::Error>((rule, removed))
+            })
+            .buffer_unordered(self.write_concurrency)
+            .try_collect()
+            .await?;
+
+        Ok(results
+            .into_iter()
+            .filter(|(_, removed)| *removed)
+            .map(|(rule, _)| rule)
+            .collect())
+    }
+
+    /// Condition under which writing a rule adds it: no item has its key, or only one that is
+    /// not visible at `generation` (written by a snapshot save that did not publish it, or
+    /// retired by the published one) or has expired.
+    fn absent_condition(&self, expr: &mut Expression, generation: i64) -> String {
+        let id = expr.name(&self.attributes.id);
+        let namespace = self.namespace_condition(expr);
+        let gen = expr.name("gen");
+        let retired = expr.name("retired");
+        let until = expr.name(VALID_UNTIL);
+        let current = expr.number(generation);
+
+        format!(
+            "attribute_not_exists({id}) OR ({namespace} AND ((attribute_exists({gen}) AND {gen} <> {current}) OR {retired} = {current} OR {until} <= {now}))",
+            id = id,
+            namespace = namespace,
+            gen = gen,
+            retired = retired,
+            current = current,
+            until = until,
+            now = expr.number(validity::now_seconds()),
+        )
+    }
+
+    fn put_rule_if_absent(
+        &self,
+        ptype: &str,
+        rule: &[String],
+        generation: i64,
+        validity: &Validity,
+    ) -> Result<Put> {
+        let mut expr = Expression::default();
+        let condition = self.absent_condition(&mut expr, generation);
+        let (names, values) = expr.into_parts();
+
+        let mut item = self.policy_to_item(ptype, rule)?;
+        self.set_validity(&mut item, validity);
+
+        Ok(Put::builder()
+            .table_name(&self.table_name)
+            .set_item(Some(item))
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .build())
+    }
+
+    /// Returns whether the rule was added, `false` if it is already stored.
+    async fn put_if_absent(
+        &self,
+        ptype: &str,
+        rule: &[String],
+        generation: i64,
+        validity: &Validity,
+    ) -> Result<bool> {
+        let put = self.put_rule_if_absent(ptype, rule, generation, validity)?;
+
+        let res = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(put.item)
+            .set_condition_expression(put.condition_expression)
+            .set_expression_attribute_names(put.expression_attribute_names)
+            .set_expression_attribute_values(put.expression_attribute_values)
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(DynamoDBAdapterError::from_sdk(
+                &self.table_name,
+                "PutItem",
+                Some((ptype, rule)),
+                e,
+            )
+            .into()),
+        }
+    }
+
+    /// Adds every rule, or none if one of them is already stored.
+    async fn add_policies_transaction(&self, ptype: &str, rules: &[Vec<String>]) -> Result<bool> {
+        let generation = self.current_generation().await?;
+
+        let mut actions = Vec::new();
+        for rule in rules {
+            actions.push(self.add_action(ptype, rule, generation)?);
+        }
+
+        self.transact_all(actions).await
+    }
+
+    /// Removes every rule, or none if one of them is not stored.
+    async fn remove_policies_transaction(
+        &self,
+        ptype: &str,
+        rules: &[Vec<String>],
+    ) -> Result<bool> {
+        let stored = self.stored_items(ptype, rules).await?;
+
+        let mut actions = Vec::new();
+        for (rule, item) in rules.iter().zip(stored) {
+            actions.push(self.remove_action(ptype, rule, item)?);
+        }
+
+        self.transact_all(actions).await
+    }
+
+    /// Reads the stored items of `rules`, in order, so that removing them can be undone.
+    async fn stored_items(&self, ptype: &str, rules: &[Vec<String>]) -> Result<Vec<Option<Item>>> {
+        stream::iter(rules.to_vec())
+            .map(|rule| async move {
+                let res = self
+                    .client
+                    .get_item()
+                    .table_name(&self.table_name)
+                    .set_key(Some(self.rule_key(ptype, &rule)?))
+                    .consistent_read(true)
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        DynamoDBAdapterError::from_sdk(
+                            &self.table_name,
+                            "GetItem",
+                            Some((ptype, &rule)),
+                            e,
+                        )
+                    })?;
+
+                Ok::<_, casbin::Error>(res.item().cloned())
+            })
+            .buffered(self.write_concurrency)
+            .try_collect()
+            .await
+    }
+
+    /// Conditional put of a new rule, undone by deleting it.
+    fn add_action(&self, ptype: &str, rule: &[String], generation: i64) -> Result<Action> {
+        let put = self.put_rule_if_absent(ptype, rule, generation, &Validity::default())?;
+        let undo = BatchEntry::delete(ptype, rule, self.rule_key(ptype, rule)?);
+
+        Ok((TransactWriteItem::builder().put(put).build(), Some(undo)))
+    }
+
+    /// Deletion of a stored rule, undone by writing back `stored`, its item as read before the
+    /// transaction, with its generation and validity window.
+    fn remove_action(&self, ptype: &str, rule: &[String], stored: Option<Item>) -> Result<Action> {
+        let mut expr = Expression::default();
+        let condition = self.stored_condition(&mut expr);
+        let (names, values) = expr.into_parts();
+
+        let delete = Delete::builder()
+            .table_name(&self.table_name)
+            .set_key(Some(self.rule_key(ptype, rule)?))
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .build();
+        // Without a stored item the condition fails and there is nothing to undo.
+        let undo = stored.map(|item| BatchEntry::put(ptype, rule, item));
+
+        Ok((TransactWriteItem::builder().delete(delete).build(), undo))
+    }
+
+    /// Check that a rule is stored, with nothing to undo.
+    fn keep_action(&self, ptype: &str, rule: &[String]) -> Result<Action> {
+        let mut expr = Expression::default();
+        let condition = self.stored_condition(&mut expr);
+        let (names, values) = expr.into_parts();
+
+        let check = ConditionCheck::builder()
+            .table_name(&self.table_name)
+            .set_key(Some(self.rule_key(ptype, rule)?))
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .build();
+
+        Ok((
+            TransactWriteItem::builder().condition_check(check).build(),
+            None,
+        ))
+    }
+
+    /// Actions replacing the stored rules `old` with `new`, which must not be stored yet, and
+    /// the changes they make.
+    ///
+    /// Rules in both sets are only checked, as a transaction may not write an item twice.
+    async fn replace_actions(
+        &self,
+        ptype: &str,
+        old: &[Vec<String>],
+        new: &[Vec<String>],
+    ) -> Result<(Vec<Action>, Vec<Change>)> {
+        let generation = self.current_generation().await?;
+
+        let mut old_ids = HashSet::new();
+        for rule in old {
+            old_ids.insert(self.get_item_id(ptype, rule)?);
+        }
+        let mut new_ids = HashSet::new();
+        for rule in new {
+            new_ids.insert(self.get_item_id(ptype, rule)?);
+        }
+
+        let mut kept = Vec::new();
+        let mut removed = Vec::new();
+        let mut seen = HashSet::new();
+        for rule in old {
+            let id = self.get_item_id(ptype, rule)?;
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+
+            if new_ids.contains(&id) {
+                kept.push(rule.clone());
+            } else {
+                removed.push(rule.clone());
+            }
+        }
+        let stored = self.stored_items(ptype, &removed).await?;
+
+        let mut actions = Vec::new();
+        let mut changes = Vec::new();
+        for rule in &kept {
+            actions.push(self.keep_action(ptype, rule)?);
+        }
+        for (rule, item) in removed.into_iter().zip(stored) {
+            actions.push(self.remove_action(ptype, &rule, item)?);
+            changes.push((AuditOperation::Remove, ptype.to_string(), rule));
+        }
+        for rule in new {
+            let id = self.get_item_id(ptype, rule)?;
+            if seen.insert(id.clone()) && !old_ids.contains(&id) {
+                actions.push(self.add_action(ptype, rule, generation)?);
+                changes.push((AuditOperation::Add, ptype.to_string(), rule.clone()));
+            }
+        }
+
+        Ok((actions, changes))
     }
 
-    fn get_item_id(&self, ptype: &str, rule: &Vec<String>) -> Result<String> {
-        let mut line = String::from(ptype);
+    /// Replaces `old_rule` with `new_rule` in one transaction.
+    ///
+    /// Returns `Ok(false)`, changing nothing, if `old_rule` is not stored or `new_rule` already
+    /// is.
+    pub async fn update_policy(
+        &self,
+        ptype: &str,
+        old_rule: Vec<String>,
+        new_rule: Vec<String>,
+    ) -> Result<bool> {
+        self.update_policies(ptype, vec![old_rule], vec![new_rule])
+            .await
+    }
 
-        for i in 0..6 {
-            if let Some(v) = rule.get(i) {
-                line.push_str(&format!(",{}", v));
-            }
+    /// Replaces each of `old_rules` with the rule at the same position in `new_rules`, all or
+    /// none of them, see `update_policy`. Lists of different lengths are rejected with
+    /// [`InvalidConfig`].
+    ///
+    /// Updates larger than `max_transaction_items` are split into several transactions and
+    /// rolled back with compensating writes if one of them fails.
+    pub async fn update_policies(
+        &self,
+        ptype: &str,
+        old_rules: Vec<Vec<String>>,
+        new_rules: Vec<Vec<String>>,
+    ) -> Result<bool> {
+        if old_rules.len() != new_rules.len() {
+            return Err(InvalidConfig(format!(
+                "{} rules can not be replaced with {} rules",
+                old_rules.len(),
+                new_rules.len()
+            ))
+            .into());
+        }
+        if old_rules.is_empty() {
+            return Ok(false);
+        }
+
+        let (actions, changes) = self.replace_actions(ptype, &old_rules, &new_rules).await?;
+        if !self.transact_all(actions).await? {
+            return Ok(false);
         }
 
-        let digest = md5::compute(line);
+        self.record("update_policies", changes).await?;
 
-        Ok(format!("{:x}", digest))
+        Ok(true)
     }
 
-    fn policy_to_item(
+    /// Replaces every rule of `ptype` matching the field filter with `new_rules`, returning the
+    /// rules removed, which are none if no rule matched.
+    ///
+    /// The replacement is all-or-nothing like `update_policies`. It returns `Ok(None)` and
+    /// changes nothing if the filter has no values, a matching rule is removed concurrently or
+    /// one of `new_rules` is already stored without matching the filter. A matching rule added
+    /// after the rules were read is neither replaced nor returned.
+    pub async fn update_filtered_policies(
         &self,
         ptype: &str,
-        rule: &Vec<String>,
-    ) -> Result<HashMap<String, AttributeValue>> {
-        let mut item: HashMap<String, AttributeValue> = HashMap::new();
+        field_index: usize,
+        field_values: Vec<String>,
+        new_rules: Vec<Vec<String>>,
+    ) -> Result<Option<Vec<Vec<String>>>> {
+        if field_values.is_empty() {
+            return Ok(None);
+        }
 
-        item.insert("pType".to_string(), AttributeValue::S(ptype.to_string()));
+        let field_values: Vec<&str> = field_values.iter().map(String::as_str).collect();
+        let sections = vec![SectionFilter::new(ptype, field_index, &field_values)];
 
-        for i in 0..6 {
-            if let Some(v) = rule.get(i) {
-                if !v.is_empty() {
-                    let key = format!("v{}", i);
-                    item.insert(key, AttributeValue::S(v.to_string()));
-                }
+        let generation = self.current_generation().await?;
+        let mut old_rules = Vec::new();
+        for mut read in self.plan_reads(&sections, self.consistent_read) {
+            let visible = self.visibility_condition(&mut read.expression, generation);
+            read.and_filter(visible);
+
+            for item in self.read_items(read).await? {
+                old_rules.push(self.item_to_policy(&item)?.1);
             }
         }
 
-        let id = self.get_item_id(ptype, &rule)?;
-        item.insert("id".to_string(), AttributeValue::S(id));
+        let (actions, changes) = self.replace_actions(ptype, &old_rules, &new_rules).await?;
+        if !self.transact_all(actions).await? {
+            return Ok(None);
+        }
 
-        Ok(item)
+        self.record("update_filtered_policies", changes).await?;
+
+        Ok(Some(old_rules))
     }
 
-    fn item_to_policy(
-        &self,
-        item: &HashMap<String, AttributeValue>,
-    ) -> Result<(String, Vec<String>)> {
-        let mut ptype = "".to_string();
-        let mut rule = Vec::new();
+    /// Applies `actions` in transactions of at most `max_transaction_items` actions.
+    ///
+    /// When a transaction fails, the compensating writes paired with the actions of the
+    /// transactions already committed are applied before returning, so either every action
+    /// or none of them persists. Returns `Ok(false)` when a condition did not hold.
+    async fn transact_all(&self, actions: Vec<Action>) -> Result<bool> {
+        let mut committed: Vec<BatchEntry> = Vec::new();
+
+        for chunk in actions.chunks(self.max_transact_items) {
+            let items = chunk.iter().map(|(action, _)| action.clone()).collect();
+            let res = self
+                .client
+                .transact_write_items()
+                .set_transact_items(Some(items))
+                .send()
+                .await;
 
-        if let Some(att) = item.get("pType") {
-            if let Some(v) = att.as_s().ok() {
-                ptype = v.to_owned();
-            }
+            let outcome = match res {
+                Ok(_) => {
+                    committed.extend(chunk.iter().filter_map(|(_, undo)| undo.clone()));
+                    continue;
+                }
+                Err(SdkError::ServiceError { err, .. }) if is_condition_failure(&err) => Ok(false),
+                Err(e) => Err(DynamoDBAdapterError::from_sdk(
+                    &self.table_name,
+                    "TransactWriteItems",
+                    None,
+                    e,
+                )
+                .into()),
+            };
+
+            self.batch_writer().write(committed).await?;
+            return outcome;
         }
 
-        if let Some(att) = item.get("v0") {
-            if let Some(v) = att.as_s().ok() {
-                rule.push(v.to_owned());
+        Ok(true)
+    }
+
+    /// Streams every stored rule as `(ptype, rule)`, one page of items in memory at a time.
+    ///
+    /// Like loads, this skips rules outside their validity window.
+    pub fn stream_policies(&self) -> impl Stream<Item = Result<(String, Vec<String>)>> + '_ {
+        stream::once(self.current_generation())
+            .map_ok(move |generation| {
+                let mut read = ReadRequest::default();
+                let visible = self.visibility_condition(&mut read.expression, generation);
+                read.and_filter(visible);
+                let active = self.active_condition(&mut read.expression, validity::now_seconds());
+                read.and_filter(active);
+                self.read_pages(read, self.consistent_read)
+            })
+            .try_flatten()
+            .map_ok(move |page| {
+                stream::iter(page.into_iter().map(move |item| self.loaded_policy(&item)))
+            })
+            .try_flatten()
+    }
+
+    /// Makes the stored policy equal to the model, writing only the rules that changed.
+    ///
+    /// This is what `save_policy` does; the returned report counts the rules added, removed
+    /// and left untouched. The stored rules are read with a strongly consistent scan of their
+    /// keys and generation and validity attributes, whatever `consistent_read` is set to.
+    ///
+    /// Changes that fit in one transaction are written with `TransactWriteItems`, which scans
+    /// only read committed, so a concurrent load may see part of them. Larger changes are
+    /// published as a new generation, which loads see either entirely or not at all. Both fail
+    /// with a conditional check error if another save published a generation since the read
+    /// or holds a claim on the next one.
+    pub async fn sync_policy(&mut self, m: &mut dyn Model) -> Result<SyncReport> {
+        let mut rules: HashMap<String, (String, Vec<String>)> = HashMap::new();
+        for sec in ["p", "g"] {
+            if let Some(ast_map) = m.get_model().get(sec) {
+                for (ptype, ast) in ast_map {
+                    for rule in ast.get_policy() {
+                        let id = self.get_item_id(ptype, rule)?;
+                        rules.insert(id, (ptype.to_string(), rule.to_vec()));
+                    }
+                }
             }
         }
 
-        if let Some(att) = item.get("v1") {
-            if let Some(v) = att.as_s().ok() {
-                rule.push(v.to_owned());
+        let generation = self.current_generation().await?;
+        let stored = self.read_stored().await?;
+
+        // A rule of the model keeps its item and validity window, so that one loaded before it
+        // expired is not stored again without it. Rules outside their window that the model
+        // does not have are kept too.
+        let now = validity::now_seconds();
+        let visible: HashSet<&str> = stored
+            .iter()
+            .filter(|item| self.is_visible(item, generation))
+            .map(|item| self.item_id(item))
+            .collect();
+
+        let mut report = SyncReport::default();
+        let mut actions = Vec::new();
+        let mut changes = Vec::new();
+        for (id, (ptype, rule)) in &rules {
+            if visible.contains(id.as_str()) {
+                report.unchanged += 1;
+                continue;
             }
-        }
 
-        if let Some(att) = item.get("v2") {
-            if let Some(v) = att.as_s().ok() {
-                rule.push(v.to_owned());
+            report.added += 1;
+            changes.push((AuditOperation::Add, ptype.clone(), rule.clone()));
+            actions.push(self.put_write(self.policy_to_item(ptype, rule)?));
+        }
+        let mut removed = Vec::new();
+        for item in &stored {
+            if self.is_visible(item, generation)
+                && self.is_active(item, now)
+                && !rules.contains_key(self.item_id(item))
+            {
+                report.removed += 1;
+                removed.extend(self.item_key(item));
+                actions.extend(self.item_key(item).map(|key| self.delete_write(key)));
             }
         }
 
-        if let Some(att) = item.get("v3") {
-            if let Some(v) = att.as_s().ok() {
-                rule.push(v.to_owned());
+        if actions.is_empty() {
+            return Ok(report);
+        }
+
+        // The stored items were read without their rule fields, only the audit needs them.
+        if self.audit_table.is_some() {
+            for item in self.items_by_key(removed).await?.into_iter().flatten() {
+                let (ptype, rule) = self.item_to_policy(&item)?;
+                changes.push((AuditOperation::Remove, ptype, rule));
             }
         }
 
-        if let Some(att) = item.get("v4") {
-            if let Some(v) = att.as_s().ok() {
-                rule.push(v.to_owned());
+        // One action of the transaction is the check of the generation item.
+        if actions.len() < self.max_transact_items {
+            actions.push(self.unclaimed_check(generation));
+            let res = self
+                .client
+                .transact_write_items()
+                .set_transact_items(Some(actions))
+                .send()
+                .await;
+            if let Err(e) = res {
+                let conflict =
+                    matches!(&e, SdkError::ServiceError { err, .. } if is_condition_failure(err));
+                let e =
+                    DynamoDBAdapterError::from_sdk(&self.table_name, "TransactWriteItems", None, e);
+                return Err(match e {
+                    DynamoDBAdapterError::Request(failed) if conflict => {
+                        DynamoDBAdapterError::ConditionalCheckFailed(failed)
+                    }
+                    e => e,
+                }
+                .into());
             }
+        } else {
+            self.save_snapshot(rules, stored, generation, now).await?;
         }
 
-        if let Some(att) = item.get("v5") {
-            if let Some(v) = att.as_s().ok() {
-                rule.push(v.to_owned());
+        self.record("save_policy", changes).await?;
+
+        Ok(report)
+    }
+
+    /// Replaces the stored policy with `rules` by publishing them as a new generation.
+    ///
+    /// The save first claims a generation, then writes the new rules invisible to readers of
+    /// the current one and marks the replaced rules as retired in the claimed one, each
+    /// transaction checking that the claim still holds. Flipping the generation item publishes
+    /// them at once, so loads see either the old or the new policy. Retired rules are kept
+    /// until the next snapshot save, for the loads that started before the flip.
+    async fn save_snapshot(
+        &self,
+        mut rules: HashMap<String, (String, Vec<String>)>,
+        stored: Vec<HashMap<String, AttributeValue>>,
+        generation: i64,
+        now: i64,
+    ) -> Result<()> {
+        let token = format!("{:016x}", fastrand::u64(..));
+        let next = self.claim_generation(generation, &token).await?;
+
+        let mut writes = Vec::new();
+        // Keys of the items tagged with `next` and of the rules retired in it, taken back if
+        // the save fails.
+        let mut tagged = Vec::new();
+        let mut marked = Vec::new();
+
+        for item in stored {
+            if self.is_generation_item(&item) {
+                continue;
+            }
+            let id = self.item_id(&item).to_string();
+            let key = match self.item_key(&item) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let visible = self.is_visible(&item, generation);
+            let active = self.is_active(&item, now);
+
+            match (rules.remove(&id), visible) {
+                (Some(_), true) => {
+                    // Unchanged, only drop the mark of an earlier snapshot save and keep any
+                    // validity window.
+                    if item.contains_key("retired") || item.contains_key("gen") {
+                        let mut expr = Expression::default();
+                        let update =
+                            format!("REMOVE {}, {}", expr.name("retired"), expr.name("gen"));
+                        writes.push(self.update_write(key, update, expr));
+                    }
+                }
+                (Some((ptype, rule)), false) => {
+                    let mut item = self.policy_to_item(&ptype, &rule)?;
+                    item.insert("gen".to_string(), AttributeValue::N(next.to_string()));
+                    writes.push(self.put_write(item));
+                    tagged.push(key);
+                }
+                (None, true) if !active => {}
+                (None, true) => {
+                    let mut expr = Expression::default();
+                    let update = format!("SET {} = {}", expr.name("retired"), expr.number(next));
+                    writes.push(self.update_write(key.clone(), update, expr));
+                    marked.push(key);
+                }
+                (None, false) => writes.push(self.delete_write(key)),
             }
         }
 
-        Ok((ptype, rule))
+        for (ptype, rule) in rules.values() {
+            let mut item = self.policy_to_item(ptype, rule)?;
+            item.insert("gen".to_string(), AttributeValue::N(next.to_string()));
+            writes.push(self.put_write(item));
+            tagged.push(self.rule_key(ptype, rule)?);
+        }
+
+        let published = match self.write_claimed(&token, writes).await {
+            Ok(()) => self.publish_generation(next, &token).await,
+            Err(e) => Err(e),
+        };
+        if published.is_err() {
+            self.abandon_generation(next, &token, tagged, marked).await;
+        }
+
+        published
     }
 
-    async fn load_filtered_policy_into_model<'f>(
+    /// Update of a stored item, which fails rather than create it if it was deleted since it
+    /// was read.
+    fn update_write(
         &self,
-        m: &mut dyn Model,
-        f: Filter<'f>,
-    ) -> Result<bool> {
-        let mut filtered = false;
+        key: HashMap<String, AttributeValue>,
+        update: String,
+        mut expr: Expression,
+    ) -> TransactWriteItem {
+        let condition = self.stored_condition(&mut expr);
+        let (names, values) = expr.into_parts();
+
+        TransactWriteItem::builder()
+            .update(
+                Update::builder()
+                    .table_name(&self.table_name)
+                    .set_key(Some(key))
+                    .update_expression(update)
+                    .condition_expression(condition)
+                    .set_expression_attribute_names(names)
+                    .set_expression_attribute_values(values)
+                    .build(),
+            )
+            .build()
+    }
+
+    fn put_write(&self, item: HashMap<String, AttributeValue>) -> TransactWriteItem {
+        let mut expr = Expression::default();
+        let condition = self.owned_condition(&mut expr);
+        let (names, values) = expr.into_parts();
+
+        TransactWriteItem::builder()
+            .put(
+                Put::builder()
+                    .table_name(&self.table_name)
+                    .set_item(Some(item))
+                    .condition_expression(condition)
+                    .set_expression_attribute_names(names)
+                    .set_expression_attribute_values(values)
+                    .build(),
+            )
+            .build()
+    }
+
+    fn delete_write(&self, key: HashMap<String, AttributeValue>) -> TransactWriteItem {
+        let mut expr = Expression::default();
+        let condition = self.owned_condition(&mut expr);
+        let (names, values) = expr.into_parts();
+
+        TransactWriteItem::builder()
+            .delete(
+                Delete::builder()
+                    .table_name(&self.table_name)
+                    .set_key(Some(key))
+                    .condition_expression(condition)
+                    .set_expression_attribute_names(names)
+                    .set_expression_attribute_values(values)
+                    .build(),
+            )
+            .build()
+    }
+
+    /// Claims the generation following every one claimed so far for a snapshot save of the
+    /// policy published as `generation`, holding it with `token`.
+    ///
+    /// Fails with a conditional check error if another save published a generation since, or
+    /// holds a claim that has not timed out.
+    async fn claim_generation(&self, generation: i64, token: &str) -> Result<i64> {
+        let mut expr = Expression::default();
+        let gen = expr.name("gen");
+        let pending = expr.name(PENDING);
+        let since = expr.name(PENDING_SINCE);
+        let claimed = expr.name(CLAIMED);
+        let current = expr.number(generation);
+        let now = validity::now_seconds();
+
+        let mut update = format!(
+            "SET {pending} = {token}, {since} = {now}, {claimed} = if_not_exists({claimed}, {current}) + {one}",
+            pending = pending,
+            token = expr.value(token),
+            since = since,
+            now = expr.number(now),
+            claimed = claimed,
+            current = current,
+            one = expr.number(1),
+        );
+        if let Some(namespace) = &self.namespace {
+            update.push_str(&format!(
+                ", {} = {}",
+                expr.name(NAMESPACE),
+                expr.value(namespace)
+            ));
+        }
+        let condition = format!(
+            "{owned} AND (attribute_not_exists({gen}) OR {gen} = {current}) AND (attribute_not_exists({pending}) OR {since} < {expired})",
+            owned = self.owned_condition(&mut expr),
+            gen = gen,
+            current = current,
+            pending = pending,
+            since = since,
+            expired = expr.number(now - CLAIM_TIMEOUT_SECONDS),
+        );
+        let (names, values) = expr.into_parts();
 
-        let items = self
+        let res = self
             .client
-            .scan()
+            .update_item()
             .table_name(&self.table_name)
-            .into_paginator()
-            .items()
+            .set_key(Some(self.generation_key()))
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .return_values(ReturnValue::UpdatedNew)
             .send()
-            .collect::<std::result::Result<Vec<_>, _>>()
             .await
-            .map_err(|e| AdapterError(Box::new(e)))?;
+            .map_err(|e| DynamoDBAdapterError::from_sdk(&self.table_name, "UpdateItem", None, e))?;
+
+        res.attributes()
+            .and_then(|item| number_attribute(item, CLAIMED))
+            .ok_or_else(|| {
+                MalformedItem {
+                    table_name: self.table_name.clone(),
+                    item_id: self.generation_id(),
+                    reason: "claimed generation missing".to_string(),
+                }
+                .into()
+            })
+    }
 
-        for item in items {
-            let (ptype, policy) = self.item_to_policy(&item)?;
-            if ptype.is_empty() || policy.is_empty() {
-                return Err(casbin::Error::from(ParsePolicyFailed(
-                    "invalid load policy".to_string(),
-                )));
-            }
+    /// Check that `generation` is still published and no snapshot save holds a claim that has
+    /// not timed out, so that a small save can not race one.
+    fn unclaimed_check(&self, generation: i64) -> TransactWriteItem {
+        let mut expr = Expression::default();
+        let condition = format!(
+            "{owned} AND (attribute_not_exists({gen}) OR {gen} = {current}) AND (attribute_not_exists({pending}) OR {since} < {expired})",
+            owned = self.owned_condition(&mut expr),
+            gen = expr.name("gen"),
+            current = expr.number(generation),
+            pending = expr.name(PENDING),
+            since = expr.name(PENDING_SINCE),
+            expired = expr.number(validity::now_seconds() - CLAIM_TIMEOUT_SECONDS),
+        );
+        let (names, values) = expr.into_parts();
+
+        let check = ConditionCheck::builder()
+            .table_name(&self.table_name)
+            .set_key(Some(self.generation_key()))
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .build();
+
+        TransactWriteItem::builder().condition_check(check).build()
+    }
+
+    /// Condition on the generation item that the claim `token` still holds.
+    fn claim_condition(&self, expr: &mut Expression, token: &str) -> String {
+        format!(
+            "{} = {} AND {}",
+            expr.name(PENDING),
+            expr.value(token),
+            self.namespace_condition(expr)
+        )
+    }
+
+    /// Writes `writes` in transactions that each check the claim `token` still holds, so that a
+    /// save whose claim was taken over can not overwrite the items of the one that took it.
+    async fn write_claimed(&self, token: &str, writes: Vec<TransactWriteItem>) -> Result<()> {
+        // One action of each transaction is the check of the claim.
+        let chunk_size = self.max_transact_items.saturating_sub(1).max(1);
+
+        for chunk in writes.chunks(chunk_size) {
+            let mut expr = Expression::default();
+            let condition = self.claim_condition(&mut expr, token);
+            let (names, values) = expr.into_parts();
+            let check = ConditionCheck::builder()
+                .table_name(&self.table_name)
+                .set_key(Some(self.generation_key()))
+                .condition_expression(condition)
+                .set_expression_attribute_names(names)
+                .set_expression_attribute_values(values)
+                .build();
+
+            let mut items = vec![TransactWriteItem::builder().condition_check(check).build()];
+            items.extend(chunk.iter().cloned());
+            self.client
+                .transact_write_items()
+                .set_transact_items(Some(items))
+                .send()
+                .await
+                .map_err(|e| {
+                    DynamoDBAdapterError::from_sdk(&self.table_name, "TransactWriteItems", None, e)
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Publishes the claimed generation `next` and releases the claim, if it still holds.
+    async fn publish_generation(&self, next: i64, token: &str) -> Result<()> {
+        let mut expr = Expression::default();
+        let update = format!(
+            "SET {} = {} REMOVE {}, {}",
+            expr.name("gen"),
+            expr.number(next),
+            expr.name(PENDING),
+            expr.name(PENDING_SINCE)
+        );
+        let condition = self.claim_condition(&mut expr, token);
+        let (names, values) = expr.into_parts();
+
+        self.client
+            .update_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.generation_key()))
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .send()
+            .await
+            .map_err(|e| DynamoDBAdapterError::from_sdk(&self.table_name, "UpdateItem", None, e))?;
+
+        Ok(())
+    }
+
+    /// Deletes the items a failed snapshot save tagged with `next`, drops the retired marks it
+    /// wrote and releases its claim.
+    ///
+    /// Every step is conditional, so items written since by others are left alone, and best
+    /// effort: what is left behind stays hidden, as `next` is never published.
+    async fn abandon_generation(
+        &self,
+        next: i64,
+        token: &str,
+        tagged: Vec<HashMap<String, AttributeValue>>,
+        marked: Vec<HashMap<String, AttributeValue>>,
+    ) {
+        let tagged_condition = |mut expr: Expression, attribute: &str| {
+            let condition = format!(
+                "{} = {} AND {}",
+                expr.name(attribute),
+                expr.number(next),
+                self.namespace_condition(&mut expr)
+            );
+            let (names, values) = expr.into_parts();
+            (condition, names, values)
+        };
+
+        stream::iter(tagged)
+            .map(|key| {
+                let (condition, names, values) = tagged_condition(Expression::default(), "gen");
+                self.client
+                    .delete_item()
+                    .table_name(&self.table_name)
+                    .set_key(Some(key))
+                    .condition_expression(condition)
+                    .set_expression_attribute_names(names)
+                    .set_expression_attribute_values(values)
+                    .send()
+            })
+            .buffer_unordered(self.write_concurrency)
+            .for_each(|_| async {})
+            .await;
+
+        stream::iter(marked)
+            .map(|key| {
+                let mut expr = Expression::default();
+                let update = format!("REMOVE {}", expr.name("retired"));
+                let (condition, names, values) = tagged_condition(expr, "retired");
+                self.client
+                    .update_item()
+                    .table_name(&self.table_name)
+                    .set_key(Some(key))
+                    .update_expression(update)
+                    .condition_expression(condition)
+                    .set_expression_attribute_names(names)
+                    .set_expression_attribute_values(values)
+                    .send()
+            })
+            .buffer_unordered(self.write_concurrency)
+            .for_each(|_| async {})
+            .await;
+
+        let mut expr = Expression::default();
+        let update = format!(
+            "REMOVE {}, {}",
+            expr.name(PENDING),
+            expr.name(PENDING_SINCE)
+        );
+        let condition = self.claim_condition(&mut expr, token);
+        let (names, values) = expr.into_parts();
+        self.client
+            .update_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.generation_key()))
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(names)
+            .set_expression_attribute_values(values)
+            .send()
+            .await
+            .ok();
+    }
+
+    /// Reads the attributes saves compare the model with of every stored item, strongly
+    /// consistent so that no write acknowledged before the call is missed.
+    async fn read_stored(&self) -> Result<Vec<Item>> {
+        let mut read = ReadRequest::default();
+        let projection: Vec<String> = [
+            self.attributes.id.as_str(),
+            self.attributes.ptype.as_str(),
+            "gen",
+            "retired",
+            VALID_FROM,
+            VALID_UNTIL,
+        ]
+        .iter()
+        .map(|attribute| read.expression.name(attribute))
+        .collect();
+        read.projection = Some(projection.join(", "));
+
+        self.read_pages(read, true).try_concat().await
+    }
+
+    /// Reads the items with `keys`, in order, strongly consistent.
+    async fn items_by_key(&self, keys: Vec<Item>) -> Result<Vec<Option<Item>>> {
+        stream::iter(keys)
+            .map(|key| async move {
+                let res = self
+                    .client
+                    .get_item()
+                    .table_name(&self.table_name)
+                    .set_key(Some(key))
+                    .consistent_read(true)
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        DynamoDBAdapterError::from_sdk(&self.table_name, "GetItem", None, e)
+                    })?;
+
+                Ok::<_, casbin::Error>(res.item().cloned())
+            })
+            .buffered(self.write_concurrency)
+            .try_collect()
+            .await
+    }
+
+    async fn read_items(&self, read: ReadRequest) -> Result<Vec<HashMap<String, AttributeValue>>> {
+        self.read_pages(read, self.consistent_read)
+            .try_concat()
+            .await
+    }
 
-            if let Some(sec) = ptype.chars().next() {
-                let mut skip_policy = false;
+    /// Plans the reads of `sections`. Global secondary indexes only support eventually
+    /// consistent reads, so consistent reads go to the table instead.
+    fn plan_reads(&self, sections: &[SectionFilter], consistent: bool) -> Vec<ReadRequest> {
+        let no_indexes = HashMap::new();
+        let indexes = if consistent {
+            &no_indexes
+        } else {
+            &self.indexes
+        };
+
+        plan_reads(sections, indexes, self.layout, &self.attributes)
+    }
+
+    /// Streams the pages returned by a read as they arrive.
+    ///
+    /// A `Scan` is split into `scan_segments` segments read concurrently, their pages are
+    /// yielded in arrival order.
+    ///
+    /// Every read is restricted to the adapter's namespace.
+    fn read_pages(
+        &self,
+        mut read: ReadRequest,
+        consistent: bool,
+    ) -> BoxStream<'static, Result<Vec<Item>>> {
+        let scope = self.namespace_condition(&mut read.expression);
+        read.and_filter(scope);
+
+        let ReadRequest {
+            index_name,
+            key_condition,
+            filter,
+            projection,
+            expression,
+        } = read;
+        let (names, values) = expression.into_parts();
+        let table_name = self.table_name.clone();
+
+        match key_condition {
+            Some(key_condition) => self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .consistent_read(consistent && index_name.is_none())
+                .set_index_name(index_name)
+                .key_condition_expression(key_condition)
+                .set_filter_expression(filter)
+                .set_projection_expression(projection)
+                .set_expression_attribute_names(names)
+                .set_expression_attribute_values(values)
+                .into_paginator()
+                .send()
+                .map_ok(|page| page.items.unwrap_or_default())
+                .map_err(move |e| {
+                    DynamoDBAdapterError::from_sdk(&table_name, "Query", None, e).into()
+                })
+                .boxed(),
+            None => {
+                let segmented = self.scan_segments > 1;
+                let segments = (0..self.scan_segments).map(|segment| {
+                    let table_name = table_name.clone();
+                    self.client
+                        .scan()
+                        .table_name(&self.table_name)
+                        .consistent_read(consistent)
+                        .set_segment(segmented.then_some(segment as i32))
+                        .set_total_segments(segmented.then_some(self.scan_segments as i32))
+                        .set_filter_expression(filter.clone())
+                        .set_projection_expression(projection.clone())
+                        .set_expression_attribute_names(names.clone())
+                        .set_expression_attribute_values(values.clone())
+                        .into_paginator()
+                        .send()
+                        .map_ok(|page| page.items.unwrap_or_default())
+                        .map_err(move |e| {
+                            DynamoDBAdapterError::from_sdk(&table_name, "Scan", None, e).into()
+                        })
+                });
+
+                stream::select_all(segments).boxed()
+            }
+        }
+    }
 
-                let f = if sec == 'p' { &f.p } else { &f.g };
-                for (i, rule) in f.iter().enumerate() {
-                    if !rule.is_empty() && rule != &policy[i] {
-                        skip_policy = true;
+    async fn load_filtered_policy_into_model(
+        &self,
+        m: &mut dyn Model,
+        filter: &PolicyFilter,
+        consistent: bool,
+    ) -> Result<bool> {
+        let sections = filter.sections(
+            ["p", "g"]
+                .iter()
+                .filter_map(|sec| m.get_model().get(*sec))
+                .flat_map(|ast_map| ast_map.keys().map(String::as_str)),
+        );
+        let filtered = sections.iter().any(|s| !s.fields.is_empty());
+
+        // Without a filter the `Id` layout reads everything in one unfiltered scan.
+        let reads = if filtered || self.layout == TableLayout::PType {
+            self.plan_reads(&sections, consistent)
+        } else {
+            vec![ReadRequest::default()]
+        };
+
+        let generation = self.current_generation().await?;
+        let now = validity::now_seconds();
+        for mut read in reads {
+            let visible = self.visibility_condition(&mut read.expression, generation);
+            read.and_filter(visible);
+            let active = self.active_condition(&mut read.expression, now);
+            read.and_filter(active);
+
+            let mut pages = self.read_pages(read, consistent);
+            while let Some(page) = pages.try_next().await? {
+                for item in page {
+                    let (ptype, policy) = self.loaded_policy(&item)?;
+
+                    let matched = sections
+                        .iter()
+                        .find(|s| s.ptype == ptype)
+                        .is_none_or(|s| s.matches(&policy));
+                    if !matched {
                         continue;
                     }
-                }
 
-                if !skip_policy {
-                    m.add_policy(&sec.to_string(), &ptype.to_string(), policy);
-                } else {
-                    filtered = true;
+                    if let Some(sec) = ptype.chars().next() {
+                        m.add_policy(&sec.to_string(), &ptype, policy);
+                    }
                 }
             }
         }
@@ -169,135 +2116,50 @@ impl DynamoDBAdapter {
 #[async_trait]
 impl Adapter for DynamoDBAdapter {
     async fn load_policy(&self, m: &mut dyn Model) -> Result<()> {
-        self.load_filtered_policy_into_model(
-            m,
-            Filter {
-                p: Vec::new(),
-                g: Vec::new(),
-            },
-        )
-        .await?;
+        self.load_filtered_policy_into_model(m, &PolicyFilter::new(), self.consistent_read)
+            .await?;
 
         Ok(())
     }
 
     async fn load_filtered_policy<'f>(&mut self, m: &mut dyn Model, f: Filter<'f>) -> Result<()> {
-        self.is_filtered = self.load_filtered_policy_into_model(m, f).await?;
+        self.is_filtered = self
+            .load_filtered_policy_into_model(m, &f.into(), self.consistent_read)
+            .await?;
 
         Ok(())
     }
 
     async fn save_policy(&mut self, m: &mut dyn Model) -> Result<()> {
-        let mut items = Vec::new();
-        for sec in vec!["p", "g"] {
-            if let Some(ast_map) = m.get_model().get(sec) {
-                for (ptype, ast) in ast_map {
-                    for rule in ast.get_policy() {
-                        let item = self.policy_to_item(ptype, rule)?;
-                        items.push(item);
-                    }
-                }
-            }
-        }
-
-        if items.is_empty() {
-            return Ok(());
-        }
-
-        let pages = (items.len() / 25) + 1;
-        for page in 0..pages {
-            let mut vec: Vec<WriteRequest> = Vec::new();
-
-            let x = page * 25;
-            let y = if x + 25 >= items.len() {
-                items.len()
-            } else {
-                x + 25
-            };
-            for i in x..y {
-                if let Some(item) = items.get(i) {
-                    vec.push(
-                        WriteRequest::builder()
-                            .put_request(
-                                PutRequest::builder()
-                                    .set_item(Some(item.to_owned()))
-                                    .build(),
-                            )
-                            .build(),
-                    );
-                }
-            }
-
-            let mut request: HashMap<String, Vec<WriteRequest>> = HashMap::new();
-            request.insert(self.table_name.to_string(), vec);
-            self.client
-                .batch_write_item()
-                .set_request_items(Some(request))
-                .send()
-                .await
-                .map_err(|e| AdapterError(Box::new(e)))?;
-        }
+        self.sync_policy(m).await?;
 
         Ok(())
     }
 
     async fn clear_policy(&mut self) -> Result<()> {
-        let items = self
-            .client
-            .scan()
-            .table_name(&self.table_name)
-            .into_paginator()
-            .items()
-            .send()
-            .collect::<std::result::Result<Vec<_>, _>>()
-            .await
-            .map_err(|e| AdapterError(Box::new(e)))?;
-
-        let mut ids: Vec<String> = Vec::new();
-        for item in items {
-            if let Some(att) = item.get("id") {
-                if let Some(v) = att.as_s().ok() {
-                    ids.push(v.to_owned());
+        let generation = self.current_generation().await?;
+        let mut call = AuditCall::new("clear_policy");
+        let mut pages = self.read_pages(ReadRequest::default(), self.consistent_read);
+        while let Some(page) = pages.try_next().await? {
+            let mut entries = Vec::new();
+            let mut changes = Vec::new();
+            for item in page {
+                // The generation item outlives a clear, so the next snapshot save still claims a
+                // generation no interrupted save has tagged rules with.
+                if self.is_generation_item(&item) {
+                    continue;
                 }
-            }
-        }
-
-        if ids.is_empty() {
-            return Ok(());
-        }
-
-        let pages = (ids.len() / 25) + 1;
-        for page in 0..pages {
-            let mut vec: Vec<WriteRequest> = Vec::new();
-
-            let x = page * 25;
-            let y = if x + 25 >= ids.len() {
-                ids.len()
-            } else {
-                x + 25
-            };
-            for i in x..y {
-                if let Some(id) = ids.get(i) {
-                    vec.push(
-                        WriteRequest::builder()
-                            .delete_request(
-                                DeleteRequest::builder()
-                                    .key("id", AttributeValue::S(id.to_owned()))
-                                    .build(),
-                            )
-                            .build(),
-                    );
+                if let Some(key) = self.item_key(&item) {
+                    let (ptype, rule) = self.item_to_policy(&item)?;
+                    if self.is_visible(&item, generation) {
+                        changes.push((AuditOperation::Remove, ptype.clone(), rule.clone()));
+                    }
+                    entries.push(BatchEntry::delete(&ptype, &rule, key));
                 }
             }
 
-            let mut request: HashMap<String, Vec<WriteRequest>> = HashMap::new();
-            request.insert(self.table_name.to_string(), vec);
-            self.client
-                .batch_write_item()
-                .set_request_items(Some(request))
-                .send()
-                .await
-                .map_err(|e| AdapterError(Box::new(e)))?;
+            self.batch_writer().write(entries).await?;
+            self.record_call(&mut call, changes).await?;
         }
 
         Ok(())
@@ -308,127 +2170,114 @@ impl Adapter for DynamoDBAdapter {
     }
 
     async fn add_policy(&mut self, _sec: &str, ptype: &str, rule: Vec<String>) -> Result<bool> {
-        let item = self.policy_to_item(ptype, &rule)?;
+        let generation = self.current_generation().await?;
 
-        self.client
-            .put_item()
-            .table_name(&self.table_name)
-            .set_item(Some(item))
-            .send()
-            .await
-            .map_err(|e| AdapterError(Box::new(e)))?;
+        if !self
+            .put_if_absent(ptype, &rule, generation, &Validity::default())
+            .await?
+        {
+            return Ok(false);
+        }
+
+        self.record(
+            "add_policy",
+            vec![(AuditOperation::Add, ptype.to_string(), rule)],
+        )
+        .await?;
 
         Ok(true)
     }
 
+    /// Adds the rules in one transaction when transactional writes are enabled, or returns
+    /// `Ok(false)` if one of them is already stored.
+    ///
+    /// Otherwise every rule is written on its own with a conditional write, at any size, and
+    /// the call returns `Ok(true)` if any rule was added. This is not atomic: the rules already
+    /// stored are left as they are, and an error leaves the rules already written in place.
     async fn add_policies(
         &mut self,
         _sec: &str,
         ptype: &str,
         rules: Vec<Vec<String>>,
     ) -> Result<bool> {
-        if rules.is_empty() {
+        let unique = self.unique_rules(ptype, rules)?;
+        if unique.is_empty() {
             return Ok(false);
         }
 
-        let pages = (rules.len() / 25) + 1;
-        for page in 0..pages {
-            let mut vec: Vec<WriteRequest> = Vec::new();
-
-            let x = page * 25;
-            let y = if x + 25 >= rules.len() {
-                rules.len()
-            } else {
-                x + 25
-            };
-            for i in x..y {
-                if let Some(rule) = rules.get(i) {
-                    let item = self.policy_to_item(ptype, rule)?;
-                    vec.push(
-                        WriteRequest::builder()
-                            .put_request(PutRequest::builder().set_item(Some(item)).build())
-                            .build(),
-                    );
-                }
+        if self.transactional {
+            if !self.add_policies_transaction(ptype, &unique).await? {
+                return Ok(false);
             }
 
-            let mut request: HashMap<String, Vec<WriteRequest>> = HashMap::new();
-            request.insert(self.table_name.to_string(), vec);
-            self.client
-                .batch_write_item()
-                .set_request_items(Some(request))
-                .send()
-                .await
-                .map_err(|e| AdapterError(Box::new(e)))?;
+            self.record("add_policies", changes(AuditOperation::Add, ptype, &unique))
+                .await?;
+
+            return Ok(true);
         }
 
-        Ok(true)
+        let added = self.put_new_policies(ptype, unique).await?;
+        self.record("add_policies", changes(AuditOperation::Add, ptype, &added))
+            .await?;
+
+        Ok(!added.is_empty())
     }
 
     async fn remove_policy(&mut self, _sec: &str, ptype: &str, rule: Vec<String>) -> Result<bool> {
-        let id = self.get_item_id(ptype, &rule)?;
-
-        let res = self
-            .client
-            .delete_item()
-            .table_name(&self.table_name)
-            .key("id", AttributeValue::S(id.to_string()))
-            .return_values(ReturnValue::AllOld)
-            .send()
-            .await
-            .map_err(|e| AdapterError(Box::new(e)))?;
-
-        if let Some(_v) = res.attributes() {
-            return Ok(true);
+        let generation = self.current_generation().await?;
+        if !self.delete_if_present(ptype, &rule, generation).await? {
+            return Ok(false);
         }
 
-        Ok(false)
+        self.record(
+            "remove_policy",
+            vec![(AuditOperation::Remove, ptype.to_string(), rule)],
+        )
+        .await?;
+
+        Ok(true)
     }
 
+    /// Removes the rules in one transaction when transactional writes are enabled, or returns
+    /// `Ok(false)` if one of them is not stored.
+    ///
+    /// Otherwise the rules are deleted in `BatchWriteItem` pages, or with one `DeleteItem` per
+    /// rule when an audit table is set, so that only the rules that were stored are recorded.
     async fn remove_policies(
         &mut self,
         _sec: &str,
         ptype: &str,
         rules: Vec<Vec<String>>,
     ) -> Result<bool> {
+        // A transaction or batch may not write the same item twice.
+        let rules = self.unique_rules(ptype, rules)?;
         if rules.is_empty() {
             return Ok(false);
         }
 
-        let pages = (rules.len() / 25) + 1;
-        for page in 0..pages {
-            let mut vec: Vec<WriteRequest> = Vec::new();
-
-            let x = page * 25;
-            let y = if x + 25 >= rules.len() {
-                rules.len()
-            } else {
-                x + 25
-            };
-            for i in x..y {
-                if let Some(rule) = rules.get(i) {
-                    let id = self.get_item_id(ptype, rule)?;
-                    vec.push(
-                        WriteRequest::builder()
-                            .delete_request(
-                                DeleteRequest::builder()
-                                    .key("id", AttributeValue::S(id))
-                                    .build(),
-                            )
-                            .build(),
-                    );
-                }
+        let removed = if self.transactional {
+            if !self.remove_policies_transaction(ptype, &rules).await? {
+                return Ok(false);
+            }
+            rules
+        } else if self.audit_table.is_some() {
+            self.delete_present(ptype, rules).await?
+        } else {
+            let mut entries = Vec::new();
+            for rule in &rules {
+                let key = self.rule_key(ptype, rule)?;
+                entries.push(BatchEntry::delete(ptype, rule, key));
             }
 
-            let mut request: HashMap<String, Vec<WriteRequest>> = HashMap::new();
-            request.insert(self.table_name.to_string(), vec);
-            self.client
-                .batch_write_item()
-                .set_request_items(Some(request))
-                .send()
-                .await
-                .map_err(|e| AdapterError(Box::new(e)))?;
-        }
+            self.batch_writer().write(entries).await?;
+            rules
+        };
+
+        self.record(
+            "remove_policies",
+            changes(AuditOperation::Remove, ptype, &removed),
+        )
+        .await?;
 
         Ok(true)
     }
@@ -444,84 +2293,59 @@ impl Adapter for DynamoDBAdapter {
             return Ok(false);
         }
 
-        let mut names = HashMap::new();
-        let mut values = HashMap::new();
-
-        let mut filter = String::from("#pType = :pType");
-        names.insert("#pType".to_string(), "pType".to_string());
-        values.insert(":pType".to_string(), AttributeValue::S(ptype.to_string()));
-
-        for (pos, val) in field_values.iter().enumerate() {
-            let i = field_index + pos;
-            if !val.is_empty() {
-                let key = format!("v{}", i);
-                filter.push_str(&format!(" AND #{} = :{}", key, key));
-                names.insert(format!("#{}", key), key.to_string());
-                values.insert(format!(":{}", key), AttributeValue::S(val.to_string()));
-            }
-        }
-
-        let items = self
-            .client
-            .scan()
-            .table_name(&self.table_name)
-            .set_filter_expression(Some(filter))
-            .set_expression_attribute_names(Some(names))
-            .set_expression_attribute_values(Some(values))
-            .into_paginator()
-            .items()
-            .send()
-            .collect::<std::result::Result<Vec<_>, _>>()
-            .await
-            .map_err(|e| AdapterError(Box::new(e)))?;
-
-        let mut ids: Vec<String> = Vec::new();
-        for item in items {
-            if let Some(att) = item.get("id") {
-                if let Some(v) = att.as_s().ok() {
-                    ids.push(v.to_owned());
+        let field_values: Vec<&str> = field_values.iter().map(String::as_str).collect();
+        let sections = vec![SectionFilter::new(ptype, field_index, &field_values)];
+
+        let generation = self.current_generation().await?;
+        let mut call = AuditCall::new("remove_filtered_policy");
+        let mut removed = false;
+        for mut read in self.plan_reads(&sections, self.consistent_read) {
+            let visible = self.visibility_condition(&mut read.expression, generation);
+            read.and_filter(visible);
+
+            let mut pages = self.read_pages(read, self.consistent_read);
+            while let Some(page) = pages.try_next().await? {
+                let mut entries = Vec::new();
+                let mut changes = Vec::new();
+                for item in page {
+                    if let Some(key) = self.item_key(&item) {
+                        let (ptype, rule) = self.item_to_policy(&item)?;
+                        changes.push((AuditOperation::Remove, ptype.clone(), rule.clone()));
+                        entries.push(BatchEntry::delete(&ptype, &rule, key));
+                    }
                 }
-            }
-        }
 
-        if ids.is_empty() {
-            return Ok(false);
+                removed |= !entries.is_empty();
+                self.batch_writer().write(entries).await?;
+                self.record_call(&mut call, changes).await?;
+            }
         }
 
-        let pages = (ids.len() / 25) + 1;
-        for page in 0..pages {
-            let mut vec: Vec<WriteRequest> = Vec::new();
-
-            let x = page * 25;
-            let y = if x + 25 >= ids.len() {
-                ids.len()
-            } else {
-                x + 25
-            };
-            for i in x..y {
-                if let Some(id) = ids.get(i) {
-                    vec.push(
-                        WriteRequest::builder()
-                            .delete_request(
-                                DeleteRequest::builder()
-                                    .key("id", AttributeValue::S(id.to_owned()))
-                                    .build(),
-                            )
-                            .build(),
-                    );
-                }
-            }
+        Ok(removed)
+    }
+}
 
-            let mut request: HashMap<String, Vec<WriteRequest>> = HashMap::new();
-            request.insert(self.table_name.to_string(), vec);
-            self.client
-                .batch_write_item()
-                .set_request_items(Some(request))
-                .send()
-                .await
-                .map_err(|e| AdapterError(Box::new(e)))?;
-        }
+fn changes(operation: AuditOperation, ptype: &str, rules: &[Vec<String>]) -> Vec<Change> {
+    rules
+        .iter()
+        .map(|rule| (operation, ptype.to_string(), rule.clone()))
+        .collect()
+}
 
-        Ok(true)
+/// Whether a transaction was cancelled because a condition did not hold.
+fn is_condition_failure(err: &TransactWriteItemsError) -> bool {
+    match &err.kind {
+        TransactWriteItemsErrorKind::TransactionCanceledException(e) => e
+            .cancellation_reasons()
+            .unwrap_or_default()
+            .iter()
+            .any(|r| r.code() == Some("ConditionalCheckFailed")),
+        _ => false,
     }
 }
+
+fn number_attribute(item: &HashMap<String, AttributeValue>, name: &str) -> Option<i64> {
+    item.get(name)
+        .and_then(|att| att.as_n().ok())
+        .and_then(|v| v.parse().ok())
+}
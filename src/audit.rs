@@ -0,0 +1,350 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_dynamodb::{
+    model::{AttributeValue, GlobalSecondaryIndex, KeyType, Projection, ProjectionType},
+    Client,
+};
+use casbin::Result;
+use futures_util::TryStreamExt;
+
+use crate::table::{self, Billing};
+use crate::{DynamoDBAdapterError, MalformedItem, TableSetupFailed};
+
+/// Partition key of the audit table: the namespace and a digest of the rule.
+pub(crate) const RULE_KEY: &str = "ruleKey";
+/// Sort key of the audit table and its subject index, ordered by time.
+pub(crate) const EVENT_KEY: &str = "eventKey";
+/// First rule field of the event, prefixed with the namespace, partition key of `SUBJECT_INDEX`.
+pub(crate) const SUBJECT: &str = "subject";
+pub(crate) const SUBJECT_INDEX: &str = "subject-index";
+
+thread_local! {
+    static CURRENT: RefCell<Option<AuditContext>> = const { RefCell::new(None) };
+}
+
+/// Whether an audited change added or removed a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOperation {
+    Add,
+    Remove,
+}
+
+impl AuditOperation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::Add => "add",
+            AuditOperation::Remove => "remove",
+        }
+    }
+}
+
+/// One rule added or removed, as recorded in the audit table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub operation: AuditOperation,
+    /// Adapter method that made the change, e.g. `save_policy`.
+    pub method: String,
+    pub ptype: String,
+    pub rule: Vec<String>,
+    /// Time of the change, with millisecond precision.
+    pub timestamp: SystemTime,
+    pub actor: Option<String>,
+    /// Shared by every event of the same request, or of the same adapter call without one.
+    pub correlation_id: String,
+}
+
+/// Actor and correlation id recorded with the changes made while a future runs, see
+/// [`AuditContext::scope`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditContext {
+    pub actor: Option<String>,
+    pub correlation_id: Option<String>,
+}
+
+impl AuditContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn actor(mut self, actor: &str) -> Self {
+        self.actor = Some(actor.to_string());
+        self
+    }
+
+    pub fn correlation_id(mut self, correlation_id: &str) -> Self {
+        self.correlation_id = Some(correlation_id.to_string());
+        self
+    }
+
+    /// Runs `future` with this context, so that the changes the adapter makes while it is
+    /// polled, e.g. through `Enforcer::add_policy`, are attributed to the actor.
+    ///
+    /// The context is only visible to the task polling `future`, not to tasks it spawns.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        Scoped {
+            context: Some(self),
+            future: Box::pin(future),
+        }
+        .await
+    }
+
+    pub(crate) fn current() -> Option<AuditContext> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+}
+
+struct Scoped<F> {
+    context: Option<AuditContext>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let previous = CURRENT.with(|current| current.replace(this.context.take()));
+
+        // Restores the enclosing context even if the future panics.
+        struct Restore<'a> {
+            context: &'a mut Option<AuditContext>,
+            previous: Option<AuditContext>,
+        }
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                *self.context = CURRENT.with(|current| current.replace(self.previous.take()));
+            }
+        }
+        let _restore = Restore {
+            context: &mut this.context,
+            previous,
+        };
+
+        this.future.as_mut().poll(cx)
+    }
+}
+
+/// Adapter call whose changes are audited, possibly page by page: all its events share the
+/// correlation id and the call id of their event keys.
+pub(crate) struct AuditCall {
+    pub method: String,
+    pub context: AuditContext,
+    pub correlation_id: String,
+    id: u64,
+    seq: usize,
+}
+
+impl AuditCall {
+    /// Starts a call of the adapter method `method`, attributed to the current `AuditContext`.
+    pub fn new(method: &str) -> Self {
+        let context = AuditContext::current().unwrap_or_default();
+        let id = fastrand::u64(..);
+        let correlation_id = context
+            .correlation_id
+            .clone()
+            .unwrap_or_else(|| format!("{:016x}", id));
+
+        Self {
+            method: method.to_string(),
+            context,
+            correlation_id,
+            id,
+            seq: 0,
+        }
+    }
+
+    /// Sort key of the next event of the call, ordered by time then by change.
+    pub fn next_event_key(&mut self, timestamp: u64) -> String {
+        self.seq += 1;
+        format!("{:013}#{:016x}#{:06}", timestamp, self.id, self.seq)
+    }
+}
+
+/// Timestamp stored with an event, in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Audit item of one change; `rule_key` and `subject` are already namespaced.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn event_item(
+    rule_key: String,
+    event_key: String,
+    subject: Option<String>,
+    operation: AuditOperation,
+    method: &str,
+    ptype: &str,
+    rule: &[String],
+    timestamp: u64,
+    context: &AuditContext,
+    correlation_id: &str,
+) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::new();
+    item.insert(RULE_KEY.to_string(), AttributeValue::S(rule_key));
+    item.insert(EVENT_KEY.to_string(), AttributeValue::S(event_key));
+    if let Some(subject) = subject {
+        item.insert(SUBJECT.to_string(), AttributeValue::S(subject));
+    }
+    item.insert(
+        "operation".to_string(),
+        AttributeValue::S(operation.as_str().to_string()),
+    );
+    item.insert("method".to_string(), AttributeValue::S(method.to_string()));
+    item.insert("pType".to_string(), AttributeValue::S(ptype.to_string()));
+    // A list keeps empty fields, which DynamoDB would reject as the value of a key.
+    item.insert(
+        "rule".to_string(),
+        AttributeValue::L(rule.iter().map(|v| AttributeValue::S(v.clone())).collect()),
+    );
+    item.insert(
+        "timestamp".to_string(),
+        AttributeValue::N(timestamp.to_string()),
+    );
+    if let Some(actor) = &context.actor {
+        item.insert("actor".to_string(), AttributeValue::S(actor.clone()));
+    }
+    item.insert(
+        "correlationId".to_string(),
+        AttributeValue::S(correlation_id.to_string()),
+    );
+
+    item
+}
+
+pub(crate) fn item_to_event(
+    table_name: &str,
+    item: &HashMap<String, AttributeValue>,
+) -> Result<AuditEvent> {
+    let string = |name: &str| item.get(name).and_then(|att| att.as_s().ok()).cloned();
+    let malformed = |reason: &str| -> casbin::Error {
+        MalformedItem {
+            table_name: table_name.to_string(),
+            item_id: string(EVENT_KEY).unwrap_or_default(),
+            reason: reason.to_string(),
+        }
+        .into()
+    };
+
+    let operation = match string("operation").as_deref() {
+        Some("add") => AuditOperation::Add,
+        Some("remove") => AuditOperation::Remove,
+        _ => return Err(malformed("unknown audit operation")),
+    };
+    let rule = item
+        .get("rule")
+        .and_then(|att| att.as_l().ok())
+        .ok_or_else(|| malformed("missing rule"))?
+        .iter()
+        .map(|v| {
+            v.as_s()
+                .cloned()
+                .map_err(|_| malformed("non-string rule field"))
+        })
+        .collect::<Result<Vec<String>>>()?;
+    let timestamp = item
+        .get("timestamp")
+        .and_then(|att| att.as_n().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| malformed("missing timestamp"))?;
+
+    Ok(AuditEvent {
+        operation,
+        method: string("method").unwrap_or_default(),
+        ptype: string("pType").ok_or_else(|| malformed("missing policy type"))?,
+        rule,
+        timestamp: UNIX_EPOCH + Duration::from_millis(timestamp),
+        actor: string("actor"),
+        correlation_id: string("correlationId").unwrap_or_default(),
+    })
+}
+
+/// Reads, oldest first, the events whose `key` (the table or index partition key) is `value`.
+pub(crate) async fn query_events(
+    client: &Client,
+    table_name: &str,
+    index_name: Option<&str>,
+    key: &str,
+    value: &str,
+) -> Result<Vec<AuditEvent>> {
+    let pages: Vec<_> = client
+        .query()
+        .table_name(table_name)
+        .set_index_name(index_name.map(str::to_string))
+        .key_condition_expression("#k = :k")
+        .expression_attribute_names("#k", key)
+        .expression_attribute_values(":k", AttributeValue::S(value.to_string()))
+        .scan_index_forward(true)
+        .into_paginator()
+        .send()
+        .try_collect()
+        .await
+        .map_err(|e| DynamoDBAdapterError::from_sdk(table_name, "Query", None, e))?;
+
+    pages
+        .iter()
+        .flat_map(|page| page.items().unwrap_or_default())
+        .map(|item| item_to_event(table_name, item))
+        .collect()
+}
+
+/// Creates the audit table and its subject index unless a table with that name exists, then
+/// waits until it is `ACTIVE` and verifies its key schema and subject index.
+pub(crate) async fn create_table_if_not_exists(
+    client: &Client,
+    table_name: &str,
+    billing: Billing,
+) -> Result<()> {
+    if table::describe_table(client, table_name).await?.is_none() {
+        create_table(client, table_name, billing).await?;
+    }
+
+    // A table created concurrently by another process may still be `CREATING`.
+    let table = table::wait_until_active(client, table_name).await?;
+    let found = table::describe_key_schema(table.key_schema().unwrap_or_default());
+    let expected = format!("{} HASH, {} RANGE", RULE_KEY, EVENT_KEY);
+    if found != expected {
+        return Err(TableSetupFailed {
+            table_name: table_name.to_string(),
+            reason: format!(
+                "key schema is [{}] but an audit table expects [{}]",
+                found, expected
+            ),
+        }
+        .into());
+    }
+
+    table::verify_index(table_name, &table, SUBJECT_INDEX, SUBJECT, EVENT_KEY)
+}
+
+async fn create_table(client: &Client, table_name: &str, billing: Billing) -> Result<()> {
+    let mut req = table::create_table_request(client, table_name, billing)
+        .key_schema(table::key_element(RULE_KEY, KeyType::Hash))
+        .key_schema(table::key_element(EVENT_KEY, KeyType::Range))
+        .global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(SUBJECT_INDEX)
+                .key_schema(table::key_element(SUBJECT, KeyType::Hash))
+                .key_schema(table::key_element(EVENT_KEY, KeyType::Range))
+                .projection(
+                    Projection::builder()
+                        .projection_type(ProjectionType::All)
+                        .build(),
+                )
+                .set_provisioned_throughput(table::provisioned_throughput(billing))
+                .build(),
+        );
+    for attribute in [RULE_KEY, EVENT_KEY, SUBJECT] {
+        req = req.attribute_definitions(table::string_attribute(attribute));
+    }
+
+    table::send_create_table(table_name, req).await
+}
@@ -0,0 +1,183 @@
+use std::{collections::HashMap, time::Duration};
+
+use aws_sdk_dynamodb::{
+    model::{AttributeValue, DeleteRequest, PutRequest, WriteRequest},
+    Client,
+};
+use casbin::Result;
+
+use crate::{BatchWriteFailed, DynamoDBAdapterError};
+
+/// Maximum number of write requests accepted by a single `BatchWriteItem` call.
+pub(crate) const MAX_BATCH_WRITE_ITEMS: usize = 25;
+
+/// Controls how `UnprocessedItems` returned by `BatchWriteItem` are resubmitted.
+///
+/// Every retry waits a random delay between zero and
+/// `min(max_delay, base_delay * 2^attempt)` (exponential backoff with full jitter).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of `BatchWriteItem` calls made for a page, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_delay);
+        let cap = exp.min(self.max_delay);
+
+        let millis = cap.as_millis() as u64;
+        if millis == 0 {
+            return cap;
+        }
+
+        Duration::from_millis(fastrand::u64(0..=millis))
+    }
+}
+
+/// A write request together with the rule it stores or deletes.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchEntry {
+    pub request: WriteRequest,
+    pub ptype: String,
+    pub rule: Vec<String>,
+}
+
+impl BatchEntry {
+    pub fn put(ptype: &str, rule: &[String], item: HashMap<String, AttributeValue>) -> Self {
+        Self {
+            request: WriteRequest::builder()
+                .put_request(PutRequest::builder().set_item(Some(item)).build())
+                .build(),
+            ptype: ptype.to_string(),
+            rule: rule.to_vec(),
+        }
+    }
+
+    pub fn delete(ptype: &str, rule: &[String], key: HashMap<String, AttributeValue>) -> Self {
+        Self {
+            request: WriteRequest::builder()
+                .delete_request(DeleteRequest::builder().set_key(Some(key)).build())
+                .build(),
+            ptype: ptype.to_string(),
+            rule: rule.to_vec(),
+        }
+    }
+}
+
+/// Sends write requests in `BatchWriteItem` pages, resubmitting unprocessed items.
+pub(crate) struct BatchWriter<'a> {
+    client: &'a Client,
+    table_name: &'a str,
+    retry: &'a RetryPolicy,
+    /// Write requests sent per `BatchWriteItem` call, at most `MAX_BATCH_WRITE_ITEMS`.
+    batch_size: usize,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn new(
+        client: &'a Client,
+        table_name: &'a str,
+        retry: &'a RetryPolicy,
+        batch_size: usize,
+    ) -> Self {
+        Self {
+            client,
+            table_name,
+            retry,
+            batch_size,
+        }
+    }
+
+    /// Writes `entries` in pages of `batch_size`; no request is sent for an empty list.
+    pub async fn write(&self, entries: Vec<BatchEntry>) -> Result<()> {
+        let mut pages = entries.chunks(self.batch_size.clamp(1, MAX_BATCH_WRITE_ITEMS));
+        let mut failed: Vec<BatchEntry> = Vec::new();
+
+        for page in pages.by_ref() {
+            let left = self.write_page(page.to_vec()).await?;
+            if !left.is_empty() {
+                failed.extend(left);
+                break;
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        // Pages after the exhausted one were never sent.
+        for page in pages {
+            failed.extend(page.iter().cloned());
+        }
+
+        Err(BatchWriteFailed {
+            table_name: self.table_name.to_string(),
+            rules: failed.into_iter().map(|e| (e.ptype, e.rule)).collect(),
+        }
+        .into())
+    }
+
+    /// Returns the entries still unprocessed once the retry budget is exhausted.
+    async fn write_page(&self, mut pending: Vec<BatchEntry>) -> Result<Vec<BatchEntry>> {
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let requests = pending.iter().map(|e| e.request.clone()).collect();
+            let res = self
+                .client
+                .batch_write_item()
+                .request_items(self.table_name, requests)
+                .send()
+                .await
+                .map_err(|e| {
+                    DynamoDBAdapterError::from_sdk(self.table_name, "BatchWriteItem", None, e)
+                })?;
+
+            let unprocessed = res
+                .unprocessed_items()
+                .and_then(|m| m.get(self.table_name))
+                .cloned()
+                .unwrap_or_default();
+
+            if unprocessed.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            pending.retain(|e| unprocessed.contains(&e.request));
+
+            if attempt >= self.retry.max_attempts {
+                return Ok(pending);
+            }
+
+            sleep(self.retry.backoff(attempt)).await;
+        }
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+pub(crate) async fn sleep(d: Duration) {
+    tokio::time::sleep(d).await;
+}
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+pub(crate) async fn sleep(d: Duration) {
+    async_std::task::sleep(d).await;
+}
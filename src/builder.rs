@@ -0,0 +1,387 @@
+use std::{collections::HashMap, sync::Arc};
+
+use aws_sdk_dynamodb::Client;
+use casbin::Result;
+
+use crate::adapter::{DynamoDBAdapter, MAX_FIELD_COUNT, MAX_TRANSACT_ITEMS, RESERVED_ATTRIBUTES};
+use crate::batch::{RetryPolicy, MAX_BATCH_WRITE_ITEMS};
+use crate::id::{IdStrategy, LegacyMd5};
+use crate::table::{AttributeNames, TableLayout};
+use crate::InvalidConfig;
+
+/// Largest number of scan segments. Every segment is read at once, so this also bounds the
+/// `Scan` requests in flight.
+const MAX_SCAN_SEGMENTS: u32 = 64;
+/// Conditional writes in flight at once when rules are added one by one.
+const DEFAULT_WRITE_CONCURRENCY: usize = 25;
+/// Audit events kept in memory after their write failed, see `flush_audit_events`.
+const DEFAULT_MAX_UNRECORDED_AUDIT: usize = 10_000;
+
+/// Configures a [`DynamoDBAdapter`], created with `DynamoDBAdapter::builder()`.
+///
+/// Every setting defaults to what `DynamoDBAdapter::new` uses; `build` rejects inconsistent
+/// settings with [`InvalidConfig`], as do the `DynamoDBAdapter::with_*` setters.
+#[derive(Debug, Clone)]
+pub struct DynamoDBAdapterBuilder {
+    client: Option<Client>,
+    table_name: Option<String>,
+    attributes: AttributeNames,
+    consistent_read: bool,
+    scan_segments: u32,
+    batch_size: usize,
+    write_concurrency: usize,
+    retry: RetryPolicy,
+    id_strategy: Arc<dyn IdStrategy>,
+    indexes: HashMap<usize, String>,
+    layout: TableLayout,
+    max_transact_items: usize,
+    max_fields: Option<usize>,
+    namespace: Option<String>,
+    transactional: bool,
+    audit_table: Option<String>,
+    audit_required: bool,
+    max_unrecorded_audit: usize,
+    ttl_attribute: Option<String>,
+}
+
+impl Default for DynamoDBAdapterBuilder {
+    fn default() -> Self {
+        Self {
+            client: None,
+            table_name: None,
+            attributes: AttributeNames::default(),
+            consistent_read: false,
+            scan_segments: 1,
+            batch_size: MAX_BATCH_WRITE_ITEMS,
+            write_concurrency: DEFAULT_WRITE_CONCURRENCY,
+            retry: RetryPolicy::default(),
+            id_strategy: Arc::new(LegacyMd5),
+            indexes: HashMap::new(),
+            layout: TableLayout::default(),
+            max_transact_items: MAX_TRANSACT_ITEMS,
+            max_fields: None,
+            namespace: None,
+            transactional: false,
+            audit_table: None,
+            audit_required: false,
+            max_unrecorded_audit: DEFAULT_MAX_UNRECORDED_AUDIT,
+            ttl_attribute: None,
+        }
+    }
+}
+
+impl DynamoDBAdapterBuilder {
+    pub fn client(mut self, client: &Client) -> Self {
+        self.client = Some(client.clone());
+        self
+    }
+
+    pub fn table_name(mut self, table_name: &str) -> Self {
+        self.table_name = Some(table_name.to_string());
+        self
+    }
+
+    /// Attribute holding the item id, `id` by default.
+    pub fn id_attribute(mut self, name: &str) -> Self {
+        self.attributes.id = name.to_string();
+        self
+    }
+
+    /// Attribute holding the policy type, `pType` by default.
+    pub fn ptype_attribute(mut self, name: &str) -> Self {
+        self.attributes.ptype = name.to_string();
+        self
+    }
+
+    /// Prefix of the attributes holding the rule fields, `v` (`v0`, `v1`, ...) by default.
+    pub fn field_prefix(mut self, prefix: &str) -> Self {
+        self.attributes.field_prefix = prefix.to_string();
+        self
+    }
+
+    /// Makes every read strongly consistent, see `DynamoDBAdapter::load_policy_consistent`.
+    pub fn consistent_read(mut self, consistent_read: bool) -> Self {
+        self.consistent_read = consistent_read;
+        self
+    }
+
+    /// Number of segments full table scans are split into, and read concurrently; at most 64.
+    pub fn scan_segments(mut self, scan_segments: u32) -> Self {
+        self.scan_segments = scan_segments;
+        self
+    }
+
+    /// Number of write requests per `BatchWriteItem` call, at most 25.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Number of single-item requests in flight: the conditional `PutItem` requests of
+    /// `add_new_policies` and non-transactional `add_policies` calls, the `DeleteItem` requests
+    /// of `remove_policies` with an audit table, and the `GetItem` requests reading the rules a
+    /// transactional removal may have to restore. Defaults to 25.
+    pub fn write_concurrency(mut self, write_concurrency: usize) -> Self {
+        self.write_concurrency = write_concurrency;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn id_strategy<S: IdStrategy + 'static>(mut self, id_strategy: S) -> Self {
+        self.id_strategy = Arc::new(id_strategy);
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_index`.
+    pub fn index(mut self, field_index: usize, index_name: &str) -> Self {
+        self.indexes.insert(field_index, index_name.to_string());
+        self
+    }
+
+    pub fn layout(mut self, layout: TableLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_max_transaction_items`.
+    pub fn max_transaction_items(mut self, max_transact_items: usize) -> Self {
+        self.max_transact_items = max_transact_items;
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_max_fields`.
+    pub fn max_fields(mut self, max_fields: usize) -> Self {
+        self.max_fields = Some(max_fields);
+        self
+    }
+
+    /// Scopes the adapter to the rules of one tenant of a shared table.
+    ///
+    /// The namespace is stored with every rule and prefixes its id; loads, removals and
+    /// `clear_policy` never see rules of other namespaces, nor those written without one.
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_transactional_writes`.
+    pub fn transactional_writes(mut self, transactional: bool) -> Self {
+        self.transactional = transactional;
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_audit_table`.
+    pub fn audit_table(mut self, table_name: &str) -> Self {
+        self.audit_table = Some(table_name.to_string());
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_audit_required`.
+    pub fn audit_required(mut self, required: bool) -> Self {
+        self.audit_required = required;
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_max_unrecorded_audit_events`.
+    pub fn max_unrecorded_audit_events(mut self, max_events: usize) -> Self {
+        self.max_unrecorded_audit = max_events;
+        self
+    }
+
+    /// See `DynamoDBAdapter::with_ttl_attribute`.
+    pub fn ttl_attribute(mut self, attribute: &str) -> Self {
+        self.ttl_attribute = Some(attribute.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<DynamoDBAdapter> {
+        let client = self
+            .client
+            .ok_or_else(|| InvalidConfig("a client is required".to_string()))?;
+        let table_name = self
+            .table_name
+            .ok_or_else(|| InvalidConfig("a table name is required".to_string()))?;
+
+        let adapter = DynamoDBAdapter {
+            client,
+            table_name,
+            is_filtered: false,
+            retry: self.retry,
+            indexes: self.indexes,
+            layout: self.layout,
+            max_transact_items: self.max_transact_items,
+            max_fields: self.max_fields,
+            id_strategy: self.id_strategy,
+            attributes: self.attributes,
+            consistent_read: self.consistent_read,
+            scan_segments: self.scan_segments,
+            batch_size: self.batch_size,
+            write_concurrency: self.write_concurrency,
+            namespace: self.namespace,
+            transactional: self.transactional,
+            audit_table: self.audit_table,
+            audit_required: self.audit_required,
+            max_unrecorded_audit: self.max_unrecorded_audit,
+            ttl_attribute: self.ttl_attribute,
+            unrecorded_audit: Arc::default(),
+        };
+        validate(&adapter)?;
+
+        Ok(adapter)
+    }
+}
+
+/// Checks the settings of an adapter, when it is built and when a `DynamoDBAdapter::with_*`
+/// setter changes one of them.
+pub(crate) fn validate(adapter: &DynamoDBAdapter) -> Result<()> {
+    validate_table_name(&adapter.table_name)?;
+    validate_attributes(&adapter.attributes)?;
+    if let Some(ttl_attribute) = &adapter.ttl_attribute {
+        validate_ttl_attribute(&adapter.attributes, ttl_attribute)?;
+    }
+    if let Some(audit_table) = &adapter.audit_table {
+        validate_table_name(audit_table)?;
+        if *audit_table == adapter.table_name {
+            return Err(InvalidConfig(format!(
+                "the audit table {} is the policy table",
+                audit_table
+            ))
+            .into());
+        }
+    }
+
+    if !(1..=MAX_SCAN_SEGMENTS).contains(&adapter.scan_segments) {
+        return Err(InvalidConfig(format!(
+            "scan segments must be between 1 and {}, got {}",
+            MAX_SCAN_SEGMENTS, adapter.scan_segments
+        ))
+        .into());
+    }
+    if !(1..=MAX_BATCH_WRITE_ITEMS).contains(&adapter.batch_size) {
+        return Err(InvalidConfig(format!(
+            "batch size must be between 1 and {}, got {}",
+            MAX_BATCH_WRITE_ITEMS, adapter.batch_size
+        ))
+        .into());
+    }
+    if adapter.write_concurrency == 0 {
+        return Err(InvalidConfig("write concurrency must be at least 1".to_string()).into());
+    }
+    if !(1..=MAX_TRANSACT_ITEMS).contains(&adapter.max_transact_items) {
+        return Err(InvalidConfig(format!(
+            "max transaction items must be between 1 and {}, got {}",
+            MAX_TRANSACT_ITEMS, adapter.max_transact_items
+        ))
+        .into());
+    }
+    if adapter.retry.max_attempts == 0 {
+        return Err(InvalidConfig("retry policy needs at least one attempt".to_string()).into());
+    }
+    if adapter.retry.base_delay > adapter.retry.max_delay {
+        return Err(
+            InvalidConfig("retry base delay is longer than its max delay".to_string()).into(),
+        );
+    }
+
+    let max_fields = adapter.max_fields.unwrap_or(MAX_FIELD_COUNT);
+    if !(1..=MAX_FIELD_COUNT).contains(&max_fields) {
+        return Err(InvalidConfig(format!(
+            "max fields must be between 1 and {}, got {}",
+            MAX_FIELD_COUNT, max_fields
+        ))
+        .into());
+    }
+    if let Some(field) = adapter.indexes.keys().find(|i| **i >= max_fields) {
+        return Err(InvalidConfig(format!(
+            "index on field {} but rules have at most {} fields",
+            field, max_fields
+        ))
+        .into());
+    }
+
+    if let Some(namespace) = &adapter.namespace {
+        if namespace.is_empty() || namespace.contains('#') {
+            return Err(InvalidConfig(format!(
+                "namespace {:?} must be non-empty and must not contain '#'",
+                namespace
+            ))
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_table_name(table_name: &str) -> Result<()> {
+    let valid_chars = table_name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+    if !(3..=255).contains(&table_name.len()) || !valid_chars {
+        return Err(InvalidConfig(format!(
+            "{:?} is not a valid table name, expected 3 to 255 characters among a-z, A-Z, 0-9, '_', '-' and '.'",
+            table_name
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+fn validate_attributes(attributes: &AttributeNames) -> Result<()> {
+    if attributes.field_prefix.is_empty() {
+        return Err(InvalidConfig("the rule field prefix is empty".to_string()).into());
+    }
+
+    for (role, name) in [("id", &attributes.id), ("policy type", &attributes.ptype)] {
+        if name.is_empty() {
+            return Err(InvalidConfig(format!("the {} attribute name is empty", role)).into());
+        }
+        if RESERVED_ATTRIBUTES.contains(&name.as_str()) {
+            return Err(InvalidConfig(format!(
+                "the {} attribute {} is reserved by the adapter",
+                role, name
+            ))
+            .into());
+        }
+        if attributes.field_index(name).is_some() {
+            return Err(InvalidConfig(format!(
+                "the {} attribute {} clashes with the rule field prefix {}",
+                role, name, attributes.field_prefix
+            ))
+            .into());
+        }
+    }
+
+    if attributes.id == attributes.ptype {
+        return Err(InvalidConfig(format!(
+            "the id and policy type attributes are both named {}",
+            attributes.id
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+fn validate_ttl_attribute(attributes: &AttributeNames, ttl_attribute: &str) -> Result<()> {
+    let clash = ttl_attribute.is_empty()
+        || RESERVED_ATTRIBUTES.contains(&ttl_attribute)
+        || ttl_attribute == attributes.id
+        || ttl_attribute == attributes.ptype
+        || attributes.field_index(ttl_attribute).is_some();
+
+    if clash {
+        return Err(InvalidConfig(format!(
+            "{:?} can not be the time to live attribute, it is empty or used by the adapter",
+            ttl_attribute
+        ))
+        .into());
+    }
+
+    Ok(())
+}
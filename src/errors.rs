@@ -1,24 +1,412 @@
+use aws_sdk_dynamodb::types::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
 use casbin::error::AdapterError;
 use casbin::Error as CasbinError;
 
+/// Error carried by the `casbin::Error::AdapterError` values returned by the adapter.
+///
+/// Use [`DynamoDBAdapterError::from_casbin`] to get it back from a `casbin::Error`.
+#[derive(Debug)]
+pub enum DynamoDBAdapterError {
+    /// The request rate or the provisioned throughput was exceeded, after the SDK's retries.
+    Throttled(RequestFailed),
+    /// The table or index does not exist.
+    ResourceNotFound(RequestFailed),
+    AccessDenied(RequestFailed),
+    /// DynamoDB rejected the request as invalid, e.g. an item or expression too large.
+    Validation(RequestFailed),
+    /// A conditional write found the item in an unexpected state.
+    ConditionalCheckFailed(RequestFailed),
+    /// Any other failed request.
+    Request(RequestFailed),
+    /// Batch writes still unprocessed once the retry policy gave up.
+    UnprocessedItems(BatchWriteFailed),
+    /// A stored item could not be read as a rule.
+    MalformedItem(MalformedItem),
+    RuleTooLong(RuleTooLong),
+    TableSetup(TableSetupFailed),
+    /// The change is stored, but its audit events could not be written.
+    AuditWrite(AuditWriteFailed),
+    InvalidConfig(InvalidConfig),
+    InvalidFilter(InvalidFilter),
+}
+
+impl DynamoDBAdapterError {
+    /// Returns the adapter error carried by `e`, if it was returned by the adapter.
+    pub fn from_casbin(e: &CasbinError) -> Option<&Self> {
+        match e {
+            CasbinError::AdapterError(AdapterError(e)) => e.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Classifies a failed SDK request by its error code.
+    pub(crate) fn from_sdk<E>(
+        table_name: &str,
+        operation: &'static str,
+        rule: Option<(&str, &[String])>,
+        e: SdkError<E>,
+    ) -> Self
+    where
+        E: ProvideErrorKind,
+        SdkError<E>: std::error::Error + Send + Sync + 'static,
+    {
+        let code = match &e {
+            SdkError::ServiceError { err, .. } => err.code().map(str::to_string),
+            _ => None,
+        };
+        let failed = RequestFailed {
+            table_name: table_name.to_string(),
+            operation,
+            rule: rule.map(|(ptype, rule)| (ptype.to_string(), rule.to_vec())),
+            source: Box::new(e),
+        };
+
+        match code.as_deref() {
+            Some("ProvisionedThroughputExceededException")
+            | Some("RequestLimitExceeded")
+            | Some("ThrottlingException") => DynamoDBAdapterError::Throttled(failed),
+            Some("ResourceNotFoundException") => DynamoDBAdapterError::ResourceNotFound(failed),
+            Some("AccessDeniedException") | Some("UnrecognizedClientException") => {
+                DynamoDBAdapterError::AccessDenied(failed)
+            }
+            Some("ValidationException") => DynamoDBAdapterError::Validation(failed),
+            Some("ConditionalCheckFailedException") => {
+                DynamoDBAdapterError::ConditionalCheckFailed(failed)
+            }
+            _ => DynamoDBAdapterError::Request(failed),
+        }
+    }
+}
+
+impl From<DynamoDBAdapterError> for CasbinError {
+    fn from(e: DynamoDBAdapterError) -> Self {
+        CasbinError::AdapterError(AdapterError(Box::new(e)))
+    }
+}
+
+impl std::fmt::Display for DynamoDBAdapterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DynamoDBAdapterError::Throttled(e) => f.write_fmt(format_args!("throttled: {}", e)),
+            DynamoDBAdapterError::ResourceNotFound(e) => {
+                f.write_fmt(format_args!("resource not found: {}", e))
+            }
+            DynamoDBAdapterError::AccessDenied(e) => {
+                f.write_fmt(format_args!("access denied: {}", e))
+            }
+            DynamoDBAdapterError::Validation(e) => {
+                f.write_fmt(format_args!("validation failed: {}", e))
+            }
+            DynamoDBAdapterError::ConditionalCheckFailed(e) => {
+                f.write_fmt(format_args!("conditional check failed: {}", e))
+            }
+            DynamoDBAdapterError::Request(e) => e.fmt(f),
+            DynamoDBAdapterError::UnprocessedItems(e) => e.fmt(f),
+            DynamoDBAdapterError::MalformedItem(e) => e.fmt(f),
+            DynamoDBAdapterError::RuleTooLong(e) => e.fmt(f),
+            DynamoDBAdapterError::TableSetup(e) => e.fmt(f),
+            DynamoDBAdapterError::AuditWrite(e) => e.fmt(f),
+            DynamoDBAdapterError::InvalidConfig(e) => e.fmt(f),
+            DynamoDBAdapterError::InvalidFilter(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DynamoDBAdapterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DynamoDBAdapterError::Throttled(e)
+            | DynamoDBAdapterError::ResourceNotFound(e)
+            | DynamoDBAdapterError::AccessDenied(e)
+            | DynamoDBAdapterError::Validation(e)
+            | DynamoDBAdapterError::ConditionalCheckFailed(e)
+            | DynamoDBAdapterError::Request(e) => Some(e.source.as_ref()),
+            DynamoDBAdapterError::AuditWrite(e) => Some(&e.source),
+            _ => None,
+        }
+    }
+}
+
+/// A DynamoDB request that failed, with the rule it was writing if there was a single one.
+pub struct RequestFailed {
+    pub table_name: String,
+    /// Name of the DynamoDB API action, e.g. `PutItem`.
+    pub operation: &'static str,
+    pub rule: Option<(String, Vec<String>)>,
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl std::fmt::Debug for RequestFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestFailed")
+            .field("table_name", &self.table_name)
+            .field("operation", &self.operation)
+            .field("rule", &self.rule)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for RequestFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} on table {} failed",
+            self.operation, self.table_name
+        ))?;
+        if let Some((ptype, rule)) = &self.rule {
+            f.write_fmt(format_args!(" for rule [{}, {}]", ptype, rule.join(", ")))?;
+        }
+        f.write_fmt(format_args!(": {}", self.source))
+    }
+}
+
+pub struct MalformedItem {
+    pub table_name: String,
+    pub item_id: String,
+    pub reason: String,
+}
+
+impl From<MalformedItem> for CasbinError {
+    fn from(e: MalformedItem) -> Self {
+        DynamoDBAdapterError::MalformedItem(e).into()
+    }
+}
+
+impl std::fmt::Debug for MalformedItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "item {} of table {} is not a rule: {}",
+            self.item_id, self.table_name, self.reason
+        ))
+    }
+}
+
+impl std::fmt::Display for MalformedItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "item {} of table {} is not a rule: {}",
+            self.item_id, self.table_name, self.reason
+        ))
+    }
+}
+
+impl std::error::Error for MalformedItem {}
+
+/// No longer returned: items that can not be read as rules fail with [`MalformedItem`].
+#[deprecated(note = "superseded by `MalformedItem`, the adapter no longer returns it")]
 pub struct ParsePolicyFailed(pub String);
 
+#[allow(deprecated)]
 impl From<ParsePolicyFailed> for CasbinError {
     fn from(e: ParsePolicyFailed) -> Self {
         CasbinError::AdapterError(AdapterError(Box::new(e)))
     }
 }
 
+#[allow(deprecated)]
 impl std::fmt::Debug for ParsePolicyFailed {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("can not parse policies: {}", self.0))
     }
 }
 
+#[allow(deprecated)]
 impl std::fmt::Display for ParsePolicyFailed {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("can not parse policies: {}", self.0))
     }
 }
 
-impl std::error::Error for ParsePolicyFailed {}
\ No newline at end of file
+#[allow(deprecated)]
+impl std::error::Error for ParsePolicyFailed {}
+
+pub struct BatchWriteFailed {
+    pub table_name: String,
+    pub rules: Vec<(String, Vec<String>)>,
+}
+
+impl From<BatchWriteFailed> for CasbinError {
+    fn from(e: BatchWriteFailed) -> Self {
+        DynamoDBAdapterError::UnprocessedItems(e).into()
+    }
+}
+
+impl std::fmt::Debug for BatchWriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BatchWriteFailed")
+            .field("table_name", &self.table_name)
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for BatchWriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} rules were not persisted to table {}:",
+            self.rules.len(),
+            self.table_name
+        ))?;
+        for (ptype, rule) in &self.rules {
+            f.write_fmt(format_args!(" [{}, {}]", ptype, rule.join(", ")))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BatchWriteFailed {}
+
+pub struct TableSetupFailed {
+    pub table_name: String,
+    pub reason: String,
+}
+
+impl From<TableSetupFailed> for CasbinError {
+    fn from(e: TableSetupFailed) -> Self {
+        DynamoDBAdapterError::TableSetup(e).into()
+    }
+}
+
+impl std::fmt::Debug for TableSetupFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "can not set up table {}: {}",
+            self.table_name, self.reason
+        ))
+    }
+}
+
+impl std::fmt::Display for TableSetupFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "can not set up table {}: {}",
+            self.table_name, self.reason
+        ))
+    }
+}
+
+impl std::error::Error for TableSetupFailed {}
+
+pub struct AuditWriteFailed {
+    pub audit_table: String,
+    /// Number of events of the call.
+    pub events: usize,
+    /// Whether the events were kept for `flush_audit_events`, or dropped because the buffer of
+    /// unrecorded events was full.
+    pub kept: bool,
+    pub source: CasbinError,
+}
+
+impl From<AuditWriteFailed> for CasbinError {
+    fn from(e: AuditWriteFailed) -> Self {
+        DynamoDBAdapterError::AuditWrite(e).into()
+    }
+}
+
+impl std::fmt::Debug for AuditWriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditWriteFailed")
+            .field("audit_table", &self.audit_table)
+            .field("events", &self.events)
+            .field("kept", &self.kept)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for AuditWriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} audit events were not written to table {} and were {}: {}",
+            self.events,
+            self.audit_table,
+            if self.kept { "kept" } else { "dropped" },
+            self.source
+        ))
+    }
+}
+
+impl std::error::Error for AuditWriteFailed {}
+
+pub struct RuleTooLong {
+    pub ptype: String,
+    pub rule: Vec<String>,
+    pub max_fields: usize,
+}
+
+impl From<RuleTooLong> for CasbinError {
+    fn from(e: RuleTooLong) -> Self {
+        DynamoDBAdapterError::RuleTooLong(e).into()
+    }
+}
+
+impl std::fmt::Debug for RuleTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "rule [{}, {}] has {} fields, the limit is {}",
+            self.ptype,
+            self.rule.join(", "),
+            self.rule.len(),
+            self.max_fields
+        ))
+    }
+}
+
+impl std::fmt::Display for RuleTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "rule [{}, {}] has {} fields, the limit is {}",
+            self.ptype,
+            self.rule.join(", "),
+            self.rule.len(),
+            self.max_fields
+        ))
+    }
+}
+
+impl std::error::Error for RuleTooLong {}
+
+pub struct InvalidConfig(pub String);
+
+impl From<InvalidConfig> for CasbinError {
+    fn from(e: InvalidConfig) -> Self {
+        DynamoDBAdapterError::InvalidConfig(e).into()
+    }
+}
+
+impl std::fmt::Debug for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("invalid adapter configuration: {}", self.0))
+    }
+}
+
+impl std::fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("invalid adapter configuration: {}", self.0))
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+pub struct InvalidFilter(pub String);
+
+impl From<InvalidFilter> for CasbinError {
+    fn from(e: InvalidFilter) -> Self {
+        DynamoDBAdapterError::InvalidFilter(e).into()
+    }
+}
+
+impl std::fmt::Debug for InvalidFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("invalid policy filter: {}", self.0))
+    }
+}
+
+impl std::fmt::Display for InvalidFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("invalid policy filter: {}", self.0))
+    }
+}
+
+impl std::error::Error for InvalidFilter {}
@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::model::AttributeValue;
+use casbin::Filter;
+#[cfg(feature = "glob")]
+use casbin::Result;
+#[cfg(feature = "glob")]
+use globset::{GlobBuilder, GlobMatcher};
+
+use crate::table::{AttributeNames, TableLayout};
+#[cfg(feature = "glob")]
+use crate::InvalidFilter;
+
+/// Collects the attribute name and value placeholders of a DynamoDB expression.
+#[derive(Debug, Default)]
+pub(crate) struct Expression {
+    names: HashMap<String, String>,
+    values: HashMap<String, AttributeValue>,
+}
+
+impl Expression {
+    pub fn name(&mut self, attribute: &str) -> String {
+        if let Some((placeholder, _)) = self.names.iter().find(|(_, a)| *a == attribute) {
+            return placeholder.clone();
+        }
+
+        // Attribute names may contain characters not allowed in placeholders.
+        let placeholder = format!("#n{}", self.names.len());
+        self.names
+            .insert(placeholder.clone(), attribute.to_string());
+        placeholder
+    }
+
+    pub fn value(&mut self, value: &str) -> String {
+        let placeholder = format!(":v{}", self.values.len());
+        self.values
+            .insert(placeholder.clone(), AttributeValue::S(value.to_string()));
+        placeholder
+    }
+
+    pub fn number(&mut self, value: i64) -> String {
+        let placeholder = format!(":v{}", self.values.len());
+        self.values
+            .insert(placeholder.clone(), AttributeValue::N(value.to_string()));
+        placeholder
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts(
+        self,
+    ) -> (
+        Option<HashMap<String, String>>,
+        Option<HashMap<String, AttributeValue>>,
+    ) {
+        let names = if self.names.is_empty() {
+            None
+        } else {
+            Some(self.names)
+        };
+        let values = if self.values.is_empty() {
+            None
+        } else {
+            Some(self.values)
+        };
+
+        (names, values)
+    }
+}
+
+/// Largest number of operands DynamoDB accepts in an `IN` comparison.
+const MAX_IN_OPERANDS: usize = 100;
+
+/// A condition on one rule field, see `PolicyFilter::field`.
+///
+/// Empty fields are stored as missing attributes, so conditions are sent to DynamoDB in a form
+/// that may return more rules than they match; each loaded rule is checked again by the adapter.
+#[derive(Debug, Clone)]
+pub enum FieldMatch {
+    Equals(String),
+    /// Matches any value but this one, only for rules that have the field.
+    NotEquals(String),
+    BeginsWith(String),
+    /// Matches any of the values, none when the list is empty.
+    In(Vec<String>),
+    /// Matches the pattern with the semantics of casbin's `globMatch`. Only the literal prefix of
+    /// the pattern is filtered by DynamoDB, the rest is evaluated by the adapter.
+    #[cfg(feature = "glob")]
+    Glob(GlobPattern),
+}
+
+impl FieldMatch {
+    /// Compiles a glob pattern, failing with [`InvalidFilter`] if it is malformed.
+    #[cfg(feature = "glob")]
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| InvalidFilter(format!("invalid glob pattern {:?}: {}", pattern, e)))?
+            .compile_matcher();
+        let prefix = pattern
+            .find(['*', '?', '[', '{', '\\'])
+            .map_or(pattern, |end| &pattern[..end])
+            .to_string();
+
+        Ok(FieldMatch::Glob(GlobPattern { matcher, prefix }))
+    }
+
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        match self {
+            FieldMatch::Equals(v) => value == v,
+            FieldMatch::NotEquals(v) => value != v,
+            FieldMatch::BeginsWith(prefix) => value.starts_with(prefix.as_str()),
+            FieldMatch::In(values) => values.iter().any(|v| v == value),
+            #[cfg(feature = "glob")]
+            FieldMatch::Glob(pattern) => pattern.matcher.is_match(value),
+        }
+    }
+
+    fn matches_nothing(&self) -> bool {
+        matches!(self, FieldMatch::In(values) if values.is_empty())
+    }
+
+    /// Value usable as the partition key of an index query, which cannot be empty.
+    fn key_values(&self) -> Option<Vec<&str>> {
+        match self {
+            FieldMatch::Equals(v) if !v.is_empty() => Some(vec![v.as_str()]),
+            FieldMatch::In(values)
+                if !values.is_empty() && values.iter().all(|v| !v.is_empty()) =>
+            {
+                let mut keys: Vec<&str> = values.iter().map(String::as_str).collect();
+                keys.sort_unstable();
+                keys.dedup();
+                Some(keys)
+            }
+            _ => None,
+        }
+    }
+
+    /// Condition returning at least the rules matched, `None` when DynamoDB cannot narrow them.
+    fn condition(&self, expr: &mut Expression, attribute: &str) -> Option<String> {
+        let name = expr.name(attribute);
+
+        match self {
+            FieldMatch::Equals(v) if v.is_empty() => {
+                Some(format!("attribute_not_exists({})", name))
+            }
+            FieldMatch::Equals(v) => Some(format!("{} = {}", name, expr.value(v))),
+            FieldMatch::NotEquals(v) if v.is_empty() => Some(format!("attribute_exists({})", name)),
+            FieldMatch::NotEquals(v) => Some(format!(
+                "(attribute_not_exists({}) OR {} <> {})",
+                name,
+                name,
+                expr.value(v)
+            )),
+            FieldMatch::BeginsWith(prefix) => begins_with(expr, &name, prefix),
+            FieldMatch::In(values) => {
+                let mut conds = Vec::new();
+                if values.iter().any(String::is_empty) {
+                    conds.push(format!("attribute_not_exists({})", name));
+                }
+                let values: Vec<&String> = values.iter().filter(|v| !v.is_empty()).collect();
+                for chunk in values.chunks(MAX_IN_OPERANDS) {
+                    let operands: Vec<String> = chunk.iter().map(|v| expr.value(v)).collect();
+                    conds.push(format!("{} IN ({})", name, operands.join(", ")));
+                }
+
+                match conds.len() {
+                    0 => None,
+                    1 => conds.pop(),
+                    _ => Some(format!("({})", conds.join(" OR "))),
+                }
+            }
+            #[cfg(feature = "glob")]
+            FieldMatch::Glob(pattern) => begins_with(expr, &name, &pattern.prefix),
+        }
+    }
+}
+
+fn begins_with(expr: &mut Expression, name: &str, prefix: &str) -> Option<String> {
+    if prefix.is_empty() {
+        return None;
+    }
+
+    Some(format!("begins_with({}, {})", name, expr.value(prefix)))
+}
+
+/// A compiled glob pattern and its literal prefix.
+#[cfg(feature = "glob")]
+#[derive(Debug, Clone)]
+pub struct GlobPattern {
+    matcher: GlobMatcher,
+    prefix: String,
+}
+
+/// Field conditions to filter each policy type by when loading, e.g. `g` and `g2` separately.
+///
+/// Rules without a field a condition applies to never match, and policy types without
+/// conditions are loaded in full.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyFilter {
+    ptypes: HashMap<String, Vec<(usize, FieldMatch)>>,
+}
+
+impl PolicyFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Filters the rules of `ptype` by equality with `values`, matched against the rule fields
+    /// from the first one; empty values match any field. Replaces the conditions already set
+    /// for `ptype`.
+    pub fn ptype(mut self, ptype: &str, values: &[&str]) -> Self {
+        self.ptypes.insert(
+            ptype.to_string(),
+            SectionFilter::new(ptype, 0, values).fields,
+        );
+        self
+    }
+
+    /// Adds a condition on the field `field_index` of the rules of `ptype`, replacing the one
+    /// already set for that field.
+    pub fn field(mut self, ptype: &str, field_index: usize, condition: FieldMatch) -> Self {
+        let fields = self.ptypes.entry(ptype.to_string()).or_default();
+        fields.retain(|(i, _)| *i != field_index);
+        fields.push((field_index, condition));
+        self
+    }
+
+    /// Section filters of the given policy types, unfiltered for those without conditions.
+    pub(crate) fn sections<'a>(
+        &self,
+        ptypes: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<SectionFilter> {
+        ptypes
+            .into_iter()
+            .map(|ptype| SectionFilter {
+                ptype: ptype.to_string(),
+                fields: self.ptypes.get(ptype).cloned().unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+/// Applies `p` to the `p` policy type and `g` to the `g` policy type only, other policy types
+/// such as `p2` or `g2` are not filtered.
+impl<'f> From<Filter<'f>> for PolicyFilter {
+    fn from(f: Filter<'f>) -> Self {
+        PolicyFilter::new().ptype("p", &f.p).ptype("g", &f.g)
+    }
+}
+
+/// Conditions on the rule fields of one policy type.
+#[derive(Debug, Clone)]
+pub(crate) struct SectionFilter {
+    pub ptype: String,
+    pub fields: Vec<(usize, FieldMatch)>,
+}
+
+impl SectionFilter {
+    /// Equality with `values` from the field `field_index`, skipping empty values.
+    pub fn new(ptype: &str, field_index: usize, values: &[&str]) -> Self {
+        let fields = values
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(pos, v)| (field_index + pos, FieldMatch::Equals(v.to_string())))
+            .collect();
+
+        Self {
+            ptype: ptype.to_string(),
+            fields,
+        }
+    }
+
+    /// Checks the rule against every condition, including those DynamoDB could not evaluate.
+    pub fn matches(&self, rule: &[String]) -> bool {
+        self.fields
+            .iter()
+            .all(|(i, m)| rule.get(*i).is_some_and(|v| m.matches(v)))
+    }
+
+    fn matches_nothing(&self) -> bool {
+        self.fields.iter().any(|(_, m)| m.matches_nothing())
+    }
+
+    fn ptype_condition(&self, expr: &mut Expression, attributes: &AttributeNames) -> String {
+        let name = expr.name(&attributes.ptype);
+        format!("{} = {}", name, expr.value(&self.ptype))
+    }
+
+    fn field_conditions(
+        &self,
+        expr: &mut Expression,
+        attributes: &AttributeNames,
+        skip: Option<usize>,
+    ) -> Vec<String> {
+        self.fields
+            .iter()
+            .filter(|(i, _)| Some(*i) != skip)
+            .filter_map(|(i, m)| m.condition(expr, &attributes.field(*i)))
+            .collect()
+    }
+
+    fn condition(
+        &self,
+        expr: &mut Expression,
+        attributes: &AttributeNames,
+        skip: Option<usize>,
+    ) -> String {
+        let mut conds = vec![self.ptype_condition(expr, attributes)];
+        conds.extend(self.field_conditions(expr, attributes, skip));
+
+        conds.join(" AND ")
+    }
+}
+
+/// A single `Scan` (no key condition) or `Query` against the table or one of its indexes.
+#[derive(Debug, Default)]
+pub(crate) struct ReadRequest {
+    pub index_name: Option<String>,
+    pub key_condition: Option<String>,
+    pub filter: Option<String>,
+    /// Attributes returned, all of them when `None`.
+    pub projection: Option<String>,
+    pub expression: Expression,
+}
+
+impl ReadRequest {
+    /// Adds a condition, built with this request's `expression`, to the filter expression.
+    pub fn and_filter(&mut self, condition: String) {
+        self.filter = Some(match self.filter.take() {
+            Some(filter) => format!("({}) AND {}", filter, condition),
+            None => condition,
+        });
+    }
+}
+
+/// Translates section filters into the cheapest set of reads returning at least the matching
+/// rules.
+///
+/// A section with an equality or `In` condition on a field backed by a registered index (keyed
+/// by field position) is read with one `Query` on that index per value and its policy type, the
+/// index's sort key. With the `PType` layout
+/// every other section queries its partition, otherwise they are folded into one filtered
+/// `Scan`. Sections that cannot match anything are not read.
+pub(crate) fn plan_reads(
+    sections: &[SectionFilter],
+    indexes: &HashMap<usize, String>,
+    layout: TableLayout,
+    attributes: &AttributeNames,
+) -> Vec<ReadRequest> {
+    let mut reads = Vec::new();
+    let mut scanned = Vec::new();
+
+    for section in sections.iter().filter(|s| !s.matches_nothing()) {
+        let indexed = section
+            .fields
+            .iter()
+            .filter(|(i, _)| indexes.contains_key(i))
+            .filter_map(|(i, m)| m.key_values().map(|keys| (*i, keys)))
+            .min_by_key(|(i, keys)| (keys.len(), *i));
+
+        match indexed {
+            Some((i, keys)) => {
+                for key in keys {
+                    // Indexes are sorted by policy type, so the query reads only its rules.
+                    let mut expression = Expression::default();
+                    let name = expression.name(&attributes.field(i));
+                    let key_condition = format!(
+                        "{} = {} AND {}",
+                        name,
+                        expression.value(key),
+                        section.ptype_condition(&mut expression, attributes)
+                    );
+                    let conds = section.field_conditions(&mut expression, attributes, Some(i));
+                    let filter = if conds.is_empty() {
+                        None
+                    } else {
+                        Some(conds.join(" AND "))
+                    };
+
+                    reads.push(ReadRequest {
+                        index_name: indexes.get(&i).cloned(),
+                        key_condition: Some(key_condition),
+                        filter,
+                        expression,
+                        ..ReadRequest::default()
+                    });
+                }
+            }
+            None if layout == TableLayout::PType => {
+                let mut expression = Expression::default();
+                let key_condition = section.ptype_condition(&mut expression, attributes);
+                let conds = section.field_conditions(&mut expression, attributes, None);
+                let filter = if conds.is_empty() {
+                    None
+                } else {
+                    Some(conds.join(" AND "))
+                };
+
+                reads.push(ReadRequest {
+                    key_condition: Some(key_condition),
+                    filter,
+                    expression,
+                    ..ReadRequest::default()
+                });
+            }
+            None => scanned.push(section),
+        }
+    }
+
+    if !scanned.is_empty() {
+        let mut expression = Expression::default();
+        let mut conds: Vec<String> = scanned
+            .iter()
+            .map(|s| s.condition(&mut expression, attributes, None))
+            .collect();
+        let filter = if conds.len() == 1 {
+            conds.remove(0)
+        } else {
+            conds
+                .iter()
+                .map(|c| format!("({})", c))
+                .collect::<Vec<_>>()
+                .join(" OR ")
+        };
+
+        reads.push(ReadRequest {
+            filter: Some(filter),
+            expression,
+            ..ReadRequest::default()
+        });
+    }
+
+    reads
+}
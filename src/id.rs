@@ -0,0 +1,68 @@
+use std::fmt::Debug;
+
+use sha2::{Digest, Sha256};
+
+/// Derives the `id` attribute stored with every rule.
+///
+/// The id must be a deterministic function of the policy type and the rule fields: removals
+/// address items by recomputing it. Changing the strategy of a populated table requires
+/// `DynamoDBAdapter::migrate_item_ids`.
+pub trait IdStrategy: Debug + Send + Sync {
+    fn item_id(&self, ptype: &str, rule: &[String]) -> String;
+}
+
+/// MD5 hex digest of `ptype,v0,v1,...`, the id used by earlier versions and the default.
+///
+/// Fields containing commas can collide, e.g. `["a,b", "c"]` and `["a", "b,c"]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LegacyMd5;
+
+impl IdStrategy for LegacyMd5 {
+    fn item_id(&self, ptype: &str, rule: &[String]) -> String {
+        let mut line = String::from(ptype);
+
+        for v in rule {
+            line.push_str(&format!(",{}", v));
+        }
+
+        format!("{:x}", md5::compute(line))
+    }
+}
+
+/// SHA-256 hex digest of the policy type and fields, each prefixed with its length in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LengthPrefixedSha256;
+
+impl IdStrategy for LengthPrefixedSha256 {
+    fn item_id(&self, ptype: &str, rule: &[String]) -> String {
+        let mut hasher = Sha256::new();
+
+        for v in std::iter::once(ptype).chain(rule.iter().map(String::as_str)) {
+            hasher.update((v.len() as u64).to_be_bytes());
+            hasher.update(v.as_bytes());
+        }
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Readable key joining the policy type and fields with `#`, e.g. `p#alice#data1#read`.
+///
+/// `#` and `\` inside values are escaped with `\`. Keys grow with the rule, and DynamoDB limits
+/// partition keys to 2048 bytes and sort keys to 1024 bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompositeKey;
+
+impl IdStrategy for CompositeKey {
+    fn item_id(&self, ptype: &str, rule: &[String]) -> String {
+        std::iter::once(ptype)
+            .chain(rule.iter().map(String::as_str))
+            .map(|v| v.replace('\\', "\\\\").replace('#', "\\#"))
+            .collect::<Vec<_>>()
+            .join("#")
+    }
+}
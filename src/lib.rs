@@ -1,35 +1,98 @@
 mod adapter;
+mod audit;
+mod batch;
+mod builder;
 mod errors;
+mod filter;
+mod id;
+mod table;
+mod validity;
+#[cfg(feature = "watcher")]
+mod watcher;
 
 pub use casbin;
 
-pub use crate::adapter::DynamoDBAdapter;
+pub use crate::adapter::{DynamoDBAdapter, SyncReport};
+pub use crate::audit::{AuditContext, AuditEvent, AuditOperation};
+pub use crate::batch::RetryPolicy;
+pub use crate::builder::DynamoDBAdapterBuilder;
+#[allow(deprecated)]
 pub use crate::errors::ParsePolicyFailed;
+pub use crate::errors::{
+    AuditWriteFailed, BatchWriteFailed, DynamoDBAdapterError, InvalidConfig, InvalidFilter,
+    MalformedItem, RequestFailed, RuleTooLong, TableSetupFailed,
+};
+#[cfg(feature = "glob")]
+pub use crate::filter::GlobPattern;
+pub use crate::filter::{FieldMatch, PolicyFilter};
+pub use crate::id::{CompositeKey, IdStrategy, LegacyMd5, LengthPrefixedSha256};
+pub use crate::table::{Billing, TableLayout, TableOptions};
+pub use crate::validity::Validity;
+#[cfg(feature = "watcher")]
+pub use crate::watcher::{DynamoDBWatcher, PolicyChange};
 
 #[cfg(test)]
 mod tests {
     use aws_sdk_dynamodb::{
-        model::{AttributeDefinition, BillingMode, KeySchemaElement, KeyType, ScalarAttributeType},
+        model::{
+            AttributeDefinition, AttributeValue, BillingMode, GlobalSecondaryIndex,
+            KeySchemaElement, KeyType, Projection, ProjectionType, ScalarAttributeType,
+        },
         Client, Endpoint,
     };
     use casbin::{
         function_map::{glob_match, key_match},
         Adapter,
     };
+    use futures_util::TryStreamExt;
     use http::Uri;
+    use std::time::Duration;
 
-    use crate::adapter::DynamoDBAdapter;
+    use crate::adapter::{DynamoDBAdapter, SyncReport};
+    use crate::audit::{AuditContext, AuditOperation, EVENT_KEY, RULE_KEY};
+    use crate::batch::{sleep, BatchEntry, BatchWriter, RetryPolicy};
+    use crate::errors::{AuditWriteFailed, DynamoDBAdapterError};
+    use crate::filter::{FieldMatch, PolicyFilter};
+    use crate::id::{CompositeKey, IdStrategy, LegacyMd5, LengthPrefixedSha256};
+    use crate::table::{Billing, TableLayout, TableOptions};
+    use crate::validity::Validity;
 
     const TABLE_NAME: &str = "Casbin_Policies";
 
+    /// The SDK's timeouts and default HTTP connector need a tokio reactor even with the
+    /// `runtime-async-std` feature, so async-std tests hold a tokio context while they run.
+    #[cfg(feature = "runtime-async-std")]
+    fn tokio_context() -> tokio::runtime::EnterGuard<'static> {
+        static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+
+        RUNTIME
+            .get_or_init(|| tokio::runtime::Runtime::new().unwrap())
+            .enter()
+    }
+
+    /// Client of the DynamoDB Local instance, with the tokio context it needs under async-std.
+    struct LocalClient {
+        client: Client,
+        #[cfg(feature = "runtime-async-std")]
+        _tokio: tokio::runtime::EnterGuard<'static>,
+    }
+
+    impl std::ops::Deref for LocalClient {
+        type Target = Client;
+
+        fn deref(&self) -> &Client {
+            &self.client
+        }
+    }
+
     fn to_owned(v: Vec<&str>) -> Vec<String> {
         v.into_iter().map(|x| x.to_owned()).collect()
     }
 
-    async fn init_table(client: &Client) {
+    async fn init_table(client: &Client, table_name: &str) {
         client
             .delete_table()
-            .table_name(TABLE_NAME.to_string())
+            .table_name(table_name.to_string())
             .send()
             .await
             .ok();
@@ -46,7 +109,7 @@ mod tests {
 
         client
             .create_table()
-            .table_name(TABLE_NAME.to_string())
+            .table_name(table_name.to_string())
             .attribute_definitions(ad)
             .key_schema(ks)
             .billing_mode(BillingMode::PayPerRequest)
@@ -55,20 +118,35 @@ mod tests {
             .ok();
     }
 
-    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
-    async fn test_adapter() -> std::result::Result<(), casbin::Error> {
-        use casbin::prelude::*;
+    fn local_endpoint() -> Endpoint {
+        Endpoint::immutable(Uri::from_static("http://localhost:8000"))
+    }
+
+    /// Client of the DynamoDB Local instance the tests run against.
+    async fn local_client() -> LocalClient {
+        #[cfg(feature = "runtime-async-std")]
+        let tokio = tokio_context();
 
         let config = aws_config::load_from_env().await;
         let dynamodb_local_config = aws_sdk_dynamodb::config::Builder::from(&config)
-            .endpoint_resolver(Endpoint::immutable(Uri::from_static(
-                "http://localhost:8000",
-            )))
+            .endpoint_resolver(local_endpoint())
             .build();
 
-        let client = Client::from_conf(dynamodb_local_config);
+        LocalClient {
+            client: Client::from_conf(dynamodb_local_config),
+            #[cfg(feature = "runtime-async-std")]
+            _tokio: tokio,
+        }
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_adapter() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
 
-        init_table(&client).await;
+        init_table(&client, TABLE_NAME).await;
 
         let file_adapter = FileAdapter::new("examples/rbac_policy.csv");
         let m = DefaultModel::from_file("examples/rbac_model.conf").await?;
@@ -182,12 +260,12 @@ mod tests {
         assert!(adapter
             .add_policy("", "g", to_owned(vec!["alice", "data2_admin"]))
             .await
-            .is_ok());
-        // In DynamoDB next call no throw error
-        assert!(adapter
+            .unwrap());
+        // The rule is already stored.
+        assert!(!adapter
             .add_policy("", "g", to_owned(vec!["alice", "data2_admin"]))
             .await
-            .is_ok());
+            .unwrap());
 
         assert!(!adapter
             .remove_filtered_policy(
@@ -249,20 +327,14 @@ mod tests {
         Ok(())
     }
 
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
     #[cfg_attr(feature = "runtime-tokio", tokio::test)]
     async fn test_casbin_adapter() -> std::result::Result<(), casbin::Error> {
         use casbin::prelude::*;
 
-        let config = aws_config::load_from_env().await;
-        let dynamodb_local_config = aws_sdk_dynamodb::config::Builder::from(&config)
-            .endpoint_resolver(Endpoint::immutable(Uri::from_static(
-                "http://localhost:8000",
-            )))
-            .build();
-
-        let client = Client::from_conf(dynamodb_local_config);
+        let client = local_client().await;
 
-        init_table(&client).await;
+        init_table(&client, TABLE_NAME).await;
 
         let m = DefaultModel::from_file("examples/rbac_model.conf").await?;
         let adapter = DynamoDBAdapter::new(&client, TABLE_NAME)?;
@@ -284,4 +356,2341 @@ mod tests {
         Ok(())
     }
 
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_batch_write() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        init_table(&client, "Casbin_Policies_Batch").await;
+
+        let mut adapter = DynamoDBAdapter::new(&client, "Casbin_Policies_Batch")?
+            .with_max_transaction_items(25)?
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                ..RetryPolicy::default()
+            })?;
+
+        let rules: Vec<Vec<String>> = (0..60)
+            .map(|i| to_owned(vec!["alice", &format!("data{}", i), "read"]))
+            .collect();
+
+        assert!(adapter.add_policies("p", "p", rules.clone()).await?);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(60, m.get_policy("p", "p").len());
+
+        // Removals and clears go through the batch writer, here in two full batches and a
+        // partial one.
+        assert!(
+            adapter
+                .remove_policies("p", "p", rules[..55].to_vec())
+                .await?
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(5, m.get_policy("p", "p").len());
+
+        adapter.clear_policy().await?;
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert!(m.get_policy("p", "p").is_empty());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_unprocessed_items() -> std::result::Result<(), casbin::Error> {
+        use aws_smithy_http::{body::SdkBody, result::ConnectorError};
+        use std::sync::{Arc, Mutex};
+
+        #[cfg(feature = "runtime-async-std")]
+        let _tokio = tokio_context();
+
+        let table_name = "Casbin_Policies_Unprocessed";
+        let alice = to_owned(vec!["alice", "data1", "read"]);
+        let bob = to_owned(vec!["bob", "data2", "write"]);
+
+        // Every call leaves bob's deletion unprocessed.
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let sent = Arc::clone(&requests);
+        let connector = tower::service_fn(move |req: http::Request<SdkBody>| {
+            let body = req.body().bytes().unwrap_or_default();
+            sent.lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(body).into_owned());
+            let res = format!(
+                r#"{{"UnprocessedItems":{{"{}":[{{"DeleteRequest":{{"Key":{{"id":{{"S":"bob"}}}}}}}}]}}}}"#,
+                table_name
+            );
+            async move {
+                Ok::<_, ConnectorError>(
+                    http::Response::builder()
+                        .status(200)
+                        .header("content-type", "application/x-amz-json-1.0")
+                        .body(SdkBody::from(res))
+                        .unwrap(),
+                )
+            }
+        });
+
+        let config = aws_config::load_from_env().await;
+        let client = Client::from_conf_conn((&config).into(), connector);
+
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+        let key = |id: &str| {
+            std::iter::once(("id".to_string(), AttributeValue::S(id.to_string()))).collect()
+        };
+        let entries = vec![
+            BatchEntry::delete("p", &alice, key("alice")),
+            BatchEntry::delete("p", &bob, key("bob")),
+        ];
+
+        let e = BatchWriter::new(&client, table_name, &retry, 25)
+            .write(entries)
+            .await
+            .unwrap_err();
+        match DynamoDBAdapterError::from_casbin(&e) {
+            Some(DynamoDBAdapterError::UnprocessedItems(failed)) => {
+                assert_eq!(table_name, failed.table_name);
+                assert_eq!(vec![("p".to_string(), bob)], failed.rules);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        // Only the unprocessed request is resubmitted, until the attempts are exhausted.
+        let requests = requests.lock().unwrap();
+        assert_eq!(3, requests.len());
+        assert!(requests[0].contains(r#""S":"alice""#));
+        for body in &requests[1..] {
+            assert!(!body.contains(r#""S":"alice""#));
+            assert!(body.contains(r#""S":"bob""#));
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_filtered_query() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Indexed";
+        client
+            .delete_table()
+            .table_name(table_name)
+            .send()
+            .await
+            .ok();
+
+        client
+            .create_table()
+            .table_name(table_name)
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("id")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build(),
+            )
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("v1")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build(),
+            )
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("pType")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build(),
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name("id")
+                    .key_type(KeyType::Hash)
+                    .build(),
+            )
+            .global_secondary_indexes(
+                GlobalSecondaryIndex::builder()
+                    .index_name("v1-index")
+                    .key_schema(
+                        KeySchemaElement::builder()
+                            .attribute_name("v1")
+                            .key_type(KeyType::Hash)
+                            .build(),
+                    )
+                    .key_schema(
+                        KeySchemaElement::builder()
+                            .attribute_name("pType")
+                            .key_type(KeyType::Range)
+                            .build(),
+                    )
+                    .projection(
+                        Projection::builder()
+                            .projection_type(ProjectionType::All)
+                            .build(),
+                    )
+                    .build(),
+            )
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await
+            .ok();
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?.with_index(1, "v1-index")?;
+
+        let mut e = Enforcer::new(
+            "examples/rbac_with_domains_model.conf",
+            "examples/rbac_with_domains_policy.csv",
+        )
+        .await?;
+
+        adapter.save_policy(e.get_mut_model()).await?;
+        e.set_adapter(adapter).await?;
+
+        e.load_filtered_policy(Filter {
+            p: vec!["", "domain1"],
+            g: vec!["", "", "domain1"],
+        })
+        .await?;
+
+        assert!(e.is_filtered());
+        assert_eq!(2, e.get_policy().len());
+        assert_eq!(1, e.get_grouping_policy().len());
+        assert!(e.enforce(("alice", "domain1", "data1", "read"))?);
+        assert!(!e.enforce(("bob", "domain2", "data2", "read"))?);
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_ptype_layout() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_PType";
+        client
+            .delete_table()
+            .table_name(table_name)
+            .send()
+            .await
+            .ok();
+
+        client
+            .create_table()
+            .table_name(table_name)
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("pType")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build(),
+            )
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("id")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build(),
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name("pType")
+                    .key_type(KeyType::Hash)
+                    .build(),
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name("id")
+                    .key_type(KeyType::Range)
+                    .build(),
+            )
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await
+            .ok();
+
+        let mut adapter =
+            DynamoDBAdapter::new(&client, table_name)?.with_layout(TableLayout::PType);
+
+        let mut e = Enforcer::new(
+            "examples/rbac_with_domains_model.conf",
+            "examples/rbac_with_domains_policy.csv",
+        )
+        .await?;
+
+        adapter.save_policy(e.get_mut_model()).await?;
+
+        assert!(
+            adapter
+                .remove_policy(
+                    "p",
+                    "p",
+                    to_owned(vec!["admin", "domain2", "data2", "write"])
+                )
+                .await?
+        );
+        assert!(
+            adapter
+                .remove_filtered_policy("g", "g", 2, to_owned(vec!["domain2"]))
+                .await?
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_with_domains_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(3, m.get_policy("p", "p").len());
+        assert_eq!(1, m.get_policy("g", "g").len());
+
+        e.set_adapter(adapter).await?;
+        e.load_filtered_policy(Filter {
+            p: vec!["", "domain1"],
+            g: vec!["", "", "domain1"],
+        })
+        .await?;
+
+        assert_eq!(2, e.get_policy().len());
+        assert!(e.enforce(("alice", "domain1", "data1", "read"))?);
+
+        e.clear_policy().await?;
+        e.load_policy().await?;
+        assert!(e.get_policy().is_empty());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_create_table() -> std::result::Result<(), casbin::Error> {
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Created";
+        client
+            .delete_table()
+            .table_name(table_name)
+            .send()
+            .await
+            .ok();
+
+        let options = TableOptions {
+            billing: Billing::Provisioned {
+                read_capacity: 5,
+                write_capacity: 5,
+            },
+            ttl_attribute: Some("expiresAt".to_string()),
+            stream: false,
+        };
+
+        let adapter = DynamoDBAdapter::new(&client, table_name)?
+            .with_layout(TableLayout::PType)
+            .with_index(1, "v1-index")?;
+
+        adapter.create_table_if_not_exists(&options).await?;
+        // The table now exists and matches.
+        adapter.create_table_if_not_exists(&options).await?;
+
+        let res = client.describe_table().table_name(table_name).send().await;
+        let table = res.unwrap().table().cloned().unwrap();
+        assert_eq!(1, table.global_secondary_indexes().unwrap().len());
+
+        let res = client.describe_time_to_live().table_name(table_name).send();
+        let ttl = res
+            .await
+            .unwrap()
+            .time_to_live_description()
+            .cloned()
+            .unwrap();
+        assert_eq!(Some("expiresAt"), ttl.attribute_name());
+
+        // Time to live is already enabled on another attribute.
+        let other = TableOptions {
+            ttl_attribute: Some("deleteAt".to_string()),
+            ..options.clone()
+        };
+        assert!(adapter.create_table_if_not_exists(&other).await.is_err());
+
+        let adapter = DynamoDBAdapter::new(&client, table_name)?;
+        assert!(adapter.create_table_if_not_exists(&options).await.is_err());
+
+        let adapter = DynamoDBAdapter::new(&client, table_name)?
+            .with_layout(TableLayout::PType)
+            .with_index(0, "v0-index")?;
+        assert!(adapter.create_table_if_not_exists(&options).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_save_policy_replaces() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Save";
+        init_table(&client, table_name).await;
+
+        let mut adapter =
+            DynamoDBAdapter::new(&client, table_name)?.with_max_transaction_items(25)?;
+
+        let stale: Vec<Vec<String>> = (0..30)
+            .map(|i| to_owned(vec!["bob", &format!("data{}", i), "write"]))
+            .collect();
+        adapter.add_policies("p", "p", stale).await?;
+
+        // A rule left behind by an interrupted snapshot save is not visible.
+        client
+            .put_item()
+            .table_name(table_name)
+            .item("id", AttributeValue::S("pending".to_string()))
+            .item("pType", AttributeValue::S("p".to_string()))
+            .item("v0", AttributeValue::S("eve".to_string()))
+            .item("v1", AttributeValue::S("data1".to_string()))
+            .item("v2", AttributeValue::S("read".to_string()))
+            .item("gen", AttributeValue::N("1".to_string()))
+            .send()
+            .await
+            .unwrap();
+
+        // 30 deletions and 5 puts do not fit in one transaction.
+        let file_adapter = FileAdapter::new("examples/rbac_policy.csv");
+        let m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        let mut e = Enforcer::new(m, file_adapter).await?;
+        adapter.save_policy(e.get_mut_model()).await?;
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(4, m.get_policy("p", "p").len());
+        assert_eq!(1, m.get_policy("g", "g").len());
+
+        let items = client.scan().table_name(table_name).send().await.unwrap();
+        // The rules, the generation item and the 29 replaced rules, kept for the loads that
+        // started before the flip until the next snapshot save.
+        assert_eq!(35, items.count());
+
+        // Small changes are applied in one transaction.
+        e.remove_policy(to_owned(vec!["alice", "data1", "read"]))
+            .await?;
+        e.add_policy(to_owned(vec!["carol", "data3", "read"]))
+            .await?;
+        let report = adapter.sync_policy(e.get_mut_model()).await?;
+        assert_eq!(
+            SyncReport {
+                added: 1,
+                removed: 1,
+                unchanged: 4,
+            },
+            report
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(4, m.get_policy("p", "p").len());
+        assert!(!m.has_policy("p", "p", to_owned(vec!["alice", "data1", "read"])));
+
+        // A rule retired by the published generation whose deletion was interrupted.
+        let retired = to_owned(vec!["bob", "data0", "write"]);
+        client
+            .put_item()
+            .table_name(table_name)
+            .item("id", AttributeValue::S(LegacyMd5.item_id("p", &retired)))
+            .item("pType", AttributeValue::S("p".to_string()))
+            .item("v0", AttributeValue::S("bob".to_string()))
+            .item("v1", AttributeValue::S("data0".to_string()))
+            .item("v2", AttributeValue::S("write".to_string()))
+            .item("retired", AttributeValue::N("1".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert!(adapter.add_policy("p", "p", retired.clone()).await?);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert!(m.has_policy("p", "p", retired));
+
+        // A snapshot save fails without writing anything while another one holds its claim.
+        let generation_key = AttributeValue::S("__casbin_generation__".to_string());
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        client
+            .update_item()
+            .table_name(table_name)
+            .key("id", generation_key.clone())
+            .update_expression("SET pending = :token, pendingSince = :now")
+            .expression_attribute_values(":token", AttributeValue::S("other".to_string()))
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await
+            .unwrap();
+
+        let fresh: Vec<Vec<String>> = (0..30)
+            .map(|i| to_owned(vec!["dave", &format!("data{}", i), "read"]))
+            .collect();
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        for rule in &fresh {
+            m.add_policy("p", "p", rule.clone());
+        }
+        let e = adapter.save_policy(&mut m).await.unwrap_err();
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&e),
+            Some(DynamoDBAdapterError::ConditionalCheckFailed(_))
+        ));
+        let items = client.scan().table_name(table_name).send().await.unwrap();
+        assert_eq!(35, items.count());
+
+        // So does a save small enough for one transaction.
+        let mut small = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        small.add_policy("p", "p", to_owned(vec!["erin", "data0", "read"]));
+        let e = adapter.save_policy(&mut small).await.unwrap_err();
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&e),
+            Some(DynamoDBAdapterError::ConditionalCheckFailed(_))
+        ));
+        let items = client.scan().table_name(table_name).send().await.unwrap();
+        assert_eq!(35, items.count());
+
+        // Once it is released, the save publishes and deletes the rules retired by the last one.
+        client
+            .update_item()
+            .table_name(table_name)
+            .key("id", generation_key.clone())
+            .update_expression("REMOVE pending, pendingSince")
+            .send()
+            .await
+            .unwrap();
+        adapter.save_policy(&mut m).await?;
+
+        let mut loaded = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut loaded).await?;
+        assert_eq!(30, loaded.get_policy("p", "p").len());
+        assert!(loaded.get_policy("g", "g").is_empty());
+        let items = client.scan().table_name(table_name).send().await.unwrap();
+        // The new rules, the 6 rules they replace and the generation item.
+        assert_eq!(37, items.count());
+
+        // A save interrupted after claiming generation 3, then a clear.
+        client
+            .update_item()
+            .table_name(table_name)
+            .key("id", generation_key.clone())
+            .update_expression("SET claimed = :claimed, pending = :token, pendingSince = :since")
+            .expression_attribute_values(":claimed", AttributeValue::N("3".to_string()))
+            .expression_attribute_values(":token", AttributeValue::S("dead".to_string()))
+            .expression_attribute_values(":since", AttributeValue::N("0".to_string()))
+            .send()
+            .await
+            .unwrap();
+        adapter.clear_policy().await?;
+        adapter.save_policy(&mut m).await?;
+
+        // A rule the interrupted save tagged after the clear stays invisible.
+        let eve = to_owned(vec!["eve", "data1", "read"]);
+        client
+            .put_item()
+            .table_name(table_name)
+            .item("id", AttributeValue::S(LegacyMd5.item_id("p", &eve)))
+            .item("pType", AttributeValue::S("p".to_string()))
+            .item("v0", AttributeValue::S("eve".to_string()))
+            .item("v1", AttributeValue::S("data1".to_string()))
+            .item("v2", AttributeValue::S("read".to_string()))
+            .item("gen", AttributeValue::N("3".to_string()))
+            .send()
+            .await
+            .unwrap();
+
+        let generation = client
+            .get_item()
+            .table_name(table_name)
+            .key("id", generation_key)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(&AttributeValue::N("4".to_string())),
+            generation.item().and_then(|item| item.get("gen"))
+        );
+        let mut loaded = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut loaded).await?;
+        assert_eq!(30, loaded.get_policy("p", "p").len());
+        assert!(!loaded.has_policy("p", "p", eve.clone()));
+        // And is not stored for a removal either.
+        assert!(!adapter.remove_policy("", "p", eve).await?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "watcher")]
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_watcher() -> std::result::Result<(), casbin::Error> {
+        use crate::table::TableOptions;
+        use crate::watcher::{DynamoDBWatcher, PolicyChange};
+        use casbin::prelude::*;
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use std::time::SystemTime;
+
+        let client = local_client().await;
+        let config = aws_config::load_from_env().await;
+        let streams_local_config = aws_sdk_dynamodbstreams::config::Builder::from(&config)
+            .endpoint_resolver(local_endpoint())
+            .build();
+        let streams_client = aws_sdk_dynamodbstreams::Client::from_conf(streams_local_config);
+
+        let table_name = "Casbin_Policies_Watched";
+        client
+            .delete_table()
+            .table_name(table_name)
+            .send()
+            .await
+            .ok();
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?;
+        adapter
+            .create_table_if_not_exists(&TableOptions {
+                stream: true,
+                ..TableOptions::default()
+            })
+            .await?;
+
+        let watcher = DynamoDBWatcher::new(&adapter, &streams_client).await?;
+
+        let updates = Arc::new(AtomicUsize::new(0));
+        let mut enforcer_watcher = watcher.clone();
+        let counter = Arc::clone(&updates);
+        enforcer_watcher.set_update_callback(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        adapter
+            .add_policy("p", "p", to_owned(vec!["alice", "data1", "read"]))
+            .await?;
+        adapter
+            .remove_policy("p", "p", to_owned(vec!["alice", "data1", "read"]))
+            .await?;
+
+        let changes = watcher.poll().await?;
+        assert_eq!(
+            vec![
+                PolicyChange::Added("p".to_string(), to_owned(vec!["alice", "data1", "read"])),
+                PolicyChange::Removed("p".to_string(), to_owned(vec!["alice", "data1", "read"])),
+            ],
+            changes
+        );
+        assert_eq!(1, updates.load(Ordering::SeqCst));
+
+        assert!(watcher.poll().await?.is_empty());
+        assert_eq!(1, watcher.checkpoints().await.len());
+
+        // Rules written by a snapshot save are published by the generation item, and followed
+        // like any other rule afterwards.
+        let mut adapter = adapter.with_max_transaction_items(1)?;
+        let bob = to_owned(vec!["bob", "data2", "write"]);
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        m.add_policy("p", "p", bob.clone());
+        m.add_policy("p", "p", to_owned(vec!["carol", "data3", "read"]));
+        adapter.save_policy(&mut m).await?;
+        assert_eq!(vec![PolicyChange::Reload], watcher.poll().await?);
+
+        assert!(adapter.remove_policy("p", "p", bob.clone()).await?);
+        // Overwriting an expired rule adds it.
+        let alice = to_owned(vec!["alice", "data1", "read"]);
+        adapter
+            .add_policy_with_expiry(
+                "p",
+                alice.clone(),
+                SystemTime::now() - Duration::from_secs(10),
+            )
+            .await?;
+        assert!(adapter.add_policy("p", "p", alice.clone()).await?);
+        assert_eq!(
+            vec![
+                PolicyChange::Removed("p".to_string(), bob),
+                PolicyChange::Added("p".to_string(), alice),
+            ],
+            watcher.poll().await?
+        );
+
+        // The changes before a record that can not be decoded are delivered with the error,
+        // and the next poll resumes after it.
+        let delivered = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut callback_watcher = watcher.clone();
+        let seen = Arc::clone(&delivered);
+        callback_watcher.set_change_callback(Box::new(move |change| {
+            seen.lock().unwrap().push(change.clone());
+        }));
+
+        let dave = to_owned(vec!["dave", "data4", "read"]);
+        let erin = to_owned(vec!["erin", "data5", "read"]);
+        assert!(adapter.add_policy("p", "p", dave.clone()).await?);
+        client
+            .put_item()
+            .table_name(table_name)
+            .item("id", AttributeValue::S("broken".to_string()))
+            .item("pType", AttributeValue::S("p".to_string()))
+            .item("v0", AttributeValue::S("mallory".to_string()))
+            .item("v2", AttributeValue::S("read".to_string()))
+            .item("fieldCount", AttributeValue::N("2".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert!(adapter.add_policy("p", "p", erin.clone()).await?);
+
+        let e = watcher.poll().await.unwrap_err();
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&e),
+            Some(DynamoDBAdapterError::MalformedItem(_))
+        ));
+        assert_eq!(
+            vec![PolicyChange::Added("p".to_string(), dave)],
+            *delivered.lock().unwrap()
+        );
+        assert_eq!(
+            vec![PolicyChange::Added("p".to_string(), erin)],
+            watcher.poll().await?
+        );
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_long_rules() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Long";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?.with_max_fields(10)?;
+
+        let rule = to_owned(vec![
+            "alice", "data1", "read", "a3", "a4", "a5", "a6", "a7", "a8", "a9",
+        ]);
+        let mut other = rule.clone();
+        other[8] = "b8".to_string();
+
+        assert!(adapter.add_policy("p", "p", rule.clone()).await?);
+        assert!(adapter.add_policy("p", "p", other.clone()).await?);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+        assert!(m.has_policy("p", "p", rule.clone()));
+        assert!(m.has_policy("p", "p", other));
+
+        assert!(
+            adapter
+                .remove_filtered_policy("p", "p", 8, to_owned(vec!["b8", "a9"]))
+                .await?
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(vec![rule.clone()], m.get_policy("p", "p"));
+
+        let mut too_long = rule;
+        too_long.push("a10".to_string());
+        assert!(adapter.add_policy("p", "p", too_long).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_empty_fields() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Empty_Fields";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?;
+
+        let gap = to_owned(vec!["alice", "", "read"]);
+        let trailing = to_owned(vec!["bob", "data2", ""]);
+        assert!(adapter.add_policy("p", "p", gap.clone()).await?);
+        assert!(adapter.add_policy("p", "p", trailing.clone()).await?);
+
+        // Written by earlier versions, without a field count.
+        client
+            .put_item()
+            .table_name(table_name)
+            .item("id", AttributeValue::S("legacy".to_string()))
+            .item("pType", AttributeValue::S("p".to_string()))
+            .item("v0", AttributeValue::S("carol".to_string()))
+            .item("v2", AttributeValue::S("write".to_string()))
+            .send()
+            .await
+            .unwrap();
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(3, m.get_policy("p", "p").len());
+        assert!(m.has_policy("p", "p", gap.clone()));
+        assert!(m.has_policy("p", "p", trailing));
+        assert!(m.has_policy("p", "p", to_owned(vec!["carol", "write"])));
+
+        assert!(adapter.remove_policy("p", "p", gap).await?);
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_id_strategy() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let left = to_owned(vec!["a,b", "c"]);
+        let right = to_owned(vec!["a", "b,c"]);
+        assert_eq!(
+            LegacyMd5.item_id("p", &left),
+            LegacyMd5.item_id("p", &right)
+        );
+        assert_ne!(
+            LengthPrefixedSha256.item_id("p", &left),
+            LengthPrefixedSha256.item_id("p", &right)
+        );
+        assert_eq!(
+            "p#alice#data1#read",
+            CompositeKey.item_id("p", &to_owned(vec!["alice", "data1", "read"]))
+        );
+        assert_ne!(
+            CompositeKey.item_id("p", &to_owned(vec!["a#b", "c"])),
+            CompositeKey.item_id("p", &to_owned(vec!["a", "b#c"]))
+        );
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Ids";
+        init_table(&client, table_name).await;
+
+        let mut legacy = DynamoDBAdapter::new(&client, table_name)?;
+        assert!(legacy.add_policy("p", "p", left.clone()).await?);
+        assert!(
+            legacy
+                .add_policy("p", "p", to_owned(vec!["alice", "data1", "read"]))
+                .await?
+        );
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?.with_id_strategy(CompositeKey);
+        assert_eq!(2, adapter.migrate_item_ids().await?);
+        assert_eq!(0, adapter.migrate_item_ids().await?);
+
+        let item = client
+            .get_item()
+            .table_name(table_name)
+            // Without a namespace, ids containing `#` start with an empty one.
+            .key("id", AttributeValue::S("#p#alice#data1#read".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert!(item.item().is_some());
+
+        assert!(adapter.add_policy("p", "p", right.clone()).await?);
+        assert!(adapter.remove_policy("p", "p", left).await?);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+        assert!(m.has_policy("p", "p", right));
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_builder() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Custom_Attributes";
+        client
+            .delete_table()
+            .table_name(table_name)
+            .send()
+            .await
+            .ok();
+
+        assert!(DynamoDBAdapter::builder()
+            .table_name(table_name)
+            .build()
+            .is_err());
+        assert!(DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name("x")
+            .build()
+            .is_err());
+        assert!(DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .batch_size(26)
+            .build()
+            .is_err());
+        assert!(DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .scan_segments(65)
+            .build()
+            .is_err());
+        assert!(DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .field_prefix("field_")
+            .ptype_attribute("field_1")
+            .build()
+            .is_err());
+        assert!(DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .id_attribute("gen")
+            .build()
+            .is_err());
+
+        // The setters of a built adapter are checked the same way.
+        let built = DynamoDBAdapter::new(&client, table_name)?;
+        assert!(built.clone().with_max_transaction_items(500).is_err());
+        assert!(built
+            .clone()
+            .with_max_fields(2)?
+            .with_index(2, "v2-index")
+            .is_err());
+        assert!(built.clone().with_audit_table(table_name).is_err());
+        assert!(built.with_ttl_attribute("id").is_err());
+
+        let mut adapter = DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .id_attribute("rule-id")
+            .ptype_attribute("policy_type")
+            .field_prefix("field_")
+            .layout(TableLayout::PType)
+            .index(0, "subject-index")
+            .consistent_read(true)
+            .scan_segments(3)
+            .max_transaction_items(25)
+            .batch_size(10)
+            .write_concurrency(4)
+            .id_strategy(CompositeKey)
+            .build()?;
+        adapter
+            .create_table_if_not_exists(&TableOptions::default())
+            .await?;
+
+        let rules: Vec<Vec<String>> = (0..30)
+            .map(|i| to_owned(vec!["alice", &format!("data{}", i), "read"]))
+            .collect();
+        assert!(adapter.add_policies("p", "p", rules).await?);
+        assert!(
+            adapter
+                .add_policy("g", "g", to_owned(vec!["alice", "admin"]))
+                .await?
+        );
+
+        let item = client
+            .get_item()
+            .table_name(table_name)
+            .key("rule-id", AttributeValue::S("g#alice#admin".to_string()))
+            .key("policy_type", AttributeValue::S("g".to_string()))
+            .send()
+            .await
+            .unwrap();
+        let item = item.item().unwrap();
+        assert_eq!(
+            Some(&AttributeValue::S("admin".to_string())),
+            item.get("field_1")
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(30, m.get_policy("p", "p").len());
+        assert_eq!(1, m.get_policy("g", "g").len());
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        let filter = Filter {
+            p: vec!["alice", "data7"],
+            g: vec![],
+        };
+        adapter.load_filtered_policy(&mut m, filter).await?;
+        assert_eq!(1, m.get_policy("p", "p").len());
+
+        assert!(
+            adapter
+                .remove_filtered_policy("p", "p", 2, to_owned(vec!["read"]))
+                .await?
+        );
+        adapter.clear_policy().await?;
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert!(m.get_policy("p", "p").is_empty());
+        assert!(m.get_policy("g", "g").is_empty());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_parallel_scan() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Segments";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .scan_segments(4)
+            .build()?;
+
+        let rules: Vec<Vec<String>> = (0..200)
+            .map(|i| to_owned(vec![&format!("user{}", i), "data1", "read"]))
+            .collect();
+        assert!(adapter.add_policies("p", "p", rules).await?);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(200, m.get_policy("p", "p").len());
+
+        let report = adapter.sync_policy(&mut m).await?;
+        assert_eq!(
+            SyncReport {
+                added: 0,
+                removed: 0,
+                unchanged: 200,
+            },
+            report
+        );
+
+        let streamed: Vec<(String, Vec<String>)> = adapter.stream_policies().try_collect().await?;
+        assert_eq!(200, streamed.len());
+        assert!(streamed.contains(&("p".to_string(), to_owned(vec!["user7", "data1", "read"]))));
+
+        adapter.clear_policy().await?;
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert!(m.get_policy("p", "p").is_empty());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_scan_segments() -> std::result::Result<(), casbin::Error> {
+        use aws_smithy_http::{body::SdkBody, result::ConnectorError};
+        use casbin::prelude::*;
+        use std::sync::{Arc, Mutex};
+
+        #[cfg(feature = "runtime-async-std")]
+        let _tokio = tokio_context();
+
+        // The generation item is missing and every segment is empty.
+        let scans = Arc::new(Mutex::new(Vec::new()));
+        let sent = Arc::clone(&scans);
+        let connector = tower::service_fn(move |req: http::Request<SdkBody>| {
+            let target = req
+                .headers()
+                .get("x-amz-target")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            let res = if target.ends_with(".Scan") {
+                let body = req.body().bytes().unwrap_or_default();
+                sent.lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(body).into_owned());
+                r#"{"Items":[],"Count":0,"ScannedCount":0}"#
+            } else {
+                "{}"
+            };
+            async move {
+                Ok::<_, ConnectorError>(
+                    http::Response::builder()
+                        .status(200)
+                        .header("content-type", "application/x-amz-json-1.0")
+                        .body(SdkBody::from(res))
+                        .unwrap(),
+                )
+            }
+        });
+
+        let config = aws_config::load_from_env().await;
+        let client = Client::from_conf_conn((&config).into(), connector);
+        let adapter = DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name("Casbin_Policies_Segmented")
+            .scan_segments(4)
+            .build()?;
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+
+        // One scan per segment, each naming its own.
+        let scans = scans.lock().unwrap();
+        assert_eq!(4, scans.len());
+        for segment in 0..4 {
+            let matching = scans
+                .iter()
+                .filter(|body| body.contains(&format!(r#""Segment":{}"#, segment)))
+                .count();
+            assert_eq!(1, matching);
+        }
+        assert!(scans
+            .iter()
+            .all(|body| body.contains(r#""TotalSegments":4"#)));
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_consistent_read() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Consistent";
+        init_table(&client, table_name).await;
+
+        // The table has no such index: consistent reads must not use it.
+        let mut adapter =
+            DynamoDBAdapter::new(&client, table_name)?.with_index(1, "missing-index")?;
+        assert!(
+            adapter
+                .add_policy("p", "p", to_owned(vec!["alice", "data1", "read"]))
+                .await?
+        );
+        assert!(
+            adapter
+                .add_policy("p", "p", to_owned(vec!["bob", "data2", "write"]))
+                .await?
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy_consistent(&mut m).await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+
+        let filter = Filter {
+            p: vec!["", "data2"],
+            g: vec![],
+        };
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        assert!(adapter
+            .load_filtered_policy(&mut m, filter.clone())
+            .await
+            .is_err());
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter
+            .load_filtered_policy_consistent(&mut m, filter)
+            .await?;
+        assert_eq!(
+            vec![to_owned(vec!["bob", "data2", "write"])],
+            m.get_policy("p", "p")
+        );
+
+        let mut adapter = adapter.with_consistent_read(true);
+        assert!(
+            adapter
+                .remove_filtered_policy("p", "p", 1, to_owned(vec!["data1"]))
+                .await?
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(1, m.get_policy("p", "p").len());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_namespace() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Shared";
+        init_table(&client, table_name).await;
+
+        assert!(DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .namespace("a#b")
+            .build()
+            .is_err());
+
+        let tenant = |namespace: &str| {
+            DynamoDBAdapter::builder()
+                .client(&client)
+                .table_name(table_name)
+                .namespace(namespace)
+                .build()
+        };
+        let mut a = tenant("tenant-a")?;
+        let mut b = tenant("tenant-b")?;
+        let mut shared = DynamoDBAdapter::new(&client, table_name)?;
+
+        let rule = to_owned(vec!["alice", "data1", "read"]);
+        for adapter in [&mut a, &mut b, &mut shared] {
+            assert!(adapter.add_policy("p", "p", rule.clone()).await?);
+            assert!(
+                adapter
+                    .add_policy("p", "p", to_owned(vec!["bob", "data2", "write"]))
+                    .await?
+            );
+        }
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        a.load_policy(&mut m).await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+
+        // Saving a's policy without bob only touches a's rules.
+        m.remove_policy("p", "p", to_owned(vec!["bob", "data2", "write"]));
+        let report = a.sync_policy(&mut m).await?;
+        assert_eq!(1, report.removed);
+
+        assert!(
+            b.remove_filtered_policy("p", "p", 0, to_owned(vec!["alice"]))
+                .await?
+        );
+        a.clear_policy().await?;
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        a.load_policy(&mut m).await?;
+        assert!(m.get_policy("p", "p").is_empty());
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        b.load_policy(&mut m).await?;
+        assert_eq!(
+            vec![to_owned(vec!["bob", "data2", "write"])],
+            m.get_policy("p", "p")
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        shared.load_policy(&mut m).await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+
+        // A namespace "p" rule and a rule without a namespace whose fields start with "p" never
+        // share a readable id.
+        let mut p = DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .namespace("p")
+            .id_strategy(CompositeKey)
+            .build()?;
+        let plain = DynamoDBAdapter::new(&client, table_name)?.with_id_strategy(CompositeKey);
+        let prefixed = to_owned(vec!["p", "alice", "data1", "read"]);
+        assert!(p.add_policy("p", "p", rule.clone()).await?);
+        assert!(!plain
+            .add_new_policies("p", vec![prefixed.clone()])
+            .await?
+            .is_empty());
+        assert!(!p.add_policy("p", "p", rule.clone()).await?);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        p.load_policy(&mut m).await?;
+        assert_eq!(vec![rule], m.get_policy("p", "p"));
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_conditional_add() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Conditional";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?;
+
+        let alice = to_owned(vec!["alice", "data1", "read"]);
+        let bob = to_owned(vec!["bob", "data2", "write"]);
+        let carol = to_owned(vec!["carol", "data3", "read"]);
+        let dave = to_owned(vec!["dave", "data4", "read"]);
+
+        assert!(adapter.add_policy("p", "p", alice.clone()).await?);
+        assert!(!adapter.add_policy("p", "p", alice.clone()).await?);
+
+        // Every rule is written on its own, and the call succeeds once a rule was added.
+        assert!(
+            adapter
+                .add_policies("p", "p", vec![bob.clone(), alice.clone()])
+                .await?
+        );
+        assert!(
+            !adapter
+                .add_policies("p", "p", vec![bob.clone(), bob.clone()])
+                .await?
+        );
+
+        // With transactional writes, nothing is written when a rule exists.
+        let mut adapter = adapter.with_transactional_writes(true);
+        assert!(
+            !adapter
+                .add_policies("p", "p", vec![carol.clone(), alice.clone()])
+                .await?
+        );
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+        assert!(
+            adapter
+                .add_policies("p", "p", vec![carol.clone(), carol.clone()])
+                .await?
+        );
+
+        let added = adapter
+            .add_new_policies("p", vec![alice.clone(), bob, carol, dave.clone()])
+            .await?;
+        assert_eq!(vec![dave], added);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(4, m.get_policy("p", "p").len());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_transactional_writes() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Transactional";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .max_transaction_items(2)
+            .transactional_writes(true)
+            .build()?;
+
+        let rules: Vec<Vec<String>> = (0..5)
+            .map(|i| to_owned(vec![&format!("user{}", i), "data1", "read"]))
+            .collect();
+        let count = |adapter: DynamoDBAdapter| async move {
+            let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+            adapter.load_policy(&mut m).await?;
+            Ok::<_, casbin::Error>(m.get_policy("p", "p").len())
+        };
+
+        // The last chunk fails, the first two are rolled back.
+        assert!(adapter.add_policy("p", "p", rules[4].clone()).await?);
+        assert!(!adapter.add_policies("p", "p", rules.clone()).await?);
+        assert_eq!(1, count(adapter.clone()).await?);
+
+        assert!(adapter.add_policies("p", "p", rules[..4].to_vec()).await?);
+        assert_eq!(5, count(adapter.clone()).await?);
+
+        let mut missing = rules.clone();
+        missing.push(to_owned(vec!["nobody", "data1", "read"]));
+        assert!(!adapter.remove_policies("p", "p", missing).await?);
+        assert_eq!(5, count(adapter.clone()).await?);
+
+        // A rule given twice is removed once.
+        let mut repeated = rules.clone();
+        repeated.push(rules[0].clone());
+        assert!(adapter.remove_policies("p", "p", repeated).await?);
+        assert_eq!(0, count(adapter.clone()).await?);
+
+        // A rolled back removal writes back the stored item, with its validity window.
+        let carol = to_owned(vec!["carol", "data1", "read"]);
+        let expires_at = std::time::SystemTime::now() + Duration::from_secs(3600);
+        assert!(
+            adapter
+                .add_policy_with_expiry("p", carol.clone(), expires_at)
+                .await?
+        );
+        assert!(adapter.add_policy("p", "p", rules[0].clone()).await?);
+        let removed = vec![
+            carol.clone(),
+            rules[0].clone(),
+            to_owned(vec!["nobody", "data1", "read"]),
+        ];
+        assert!(!adapter.remove_policies("p", "p", removed).await?);
+        assert_eq!(2, count(adapter.clone()).await?);
+
+        let item = client
+            .get_item()
+            .table_name(table_name)
+            .key("id", AttributeValue::S(LegacyMd5.item_id("p", &carol)))
+            .send()
+            .await
+            .unwrap();
+        assert!(item.item().and_then(|i| i.get("validUntil")).is_some());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_update_policies() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Update";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?;
+
+        let alice = to_owned(vec!["alice", "data1", "read"]);
+        let alice_write = to_owned(vec!["alice", "data1", "write"]);
+        let bob = to_owned(vec!["bob", "data2", "write"]);
+        let carol = to_owned(vec!["carol", "data2", "read"]);
+        let dave = to_owned(vec!["dave", "data3", "read"]);
+        assert!(
+            adapter
+                .add_policies("p", "p", vec![alice.clone(), bob.clone()])
+                .await?
+        );
+
+        assert!(
+            adapter
+                .update_policy("p", alice.clone(), alice_write.clone())
+                .await?
+        );
+        // The old rule is gone, and the new one can not overwrite bob.
+        assert!(
+            !adapter
+                .update_policy("p", alice.clone(), carol.clone())
+                .await?
+        );
+        assert!(
+            !adapter
+                .update_policy("p", alice_write.clone(), bob.clone())
+                .await?
+        );
+
+        // Swapping rules writes each item once.
+        assert!(
+            adapter
+                .update_policies(
+                    "p",
+                    vec![alice_write.clone(), bob.clone()],
+                    vec![bob.clone(), alice_write.clone()],
+                )
+                .await?
+        );
+
+        assert!(adapter
+            .update_policies("p", vec![bob.clone()], Vec::new())
+            .await
+            .is_err());
+
+        let removed = adapter
+            .update_filtered_policies(
+                "p",
+                1,
+                to_owned(vec!["data2"]),
+                vec![carol.clone(), alice.clone()],
+            )
+            .await?;
+        assert_eq!(Some(vec![bob]), removed);
+
+        // Without a filter nothing is replaced, without a match the new rules are only added.
+        assert_eq!(
+            None,
+            adapter
+                .update_filtered_policies("p", 1, Vec::new(), vec![dave.clone()])
+                .await?
+        );
+        assert_eq!(
+            None,
+            adapter
+                .update_filtered_policies("p", 1, to_owned(vec!["data9"]), vec![carol.clone()])
+                .await?
+        );
+        assert_eq!(
+            Some(Vec::new()),
+            adapter
+                .update_filtered_policies("p", 1, to_owned(vec!["data9"]), vec![dave.clone()])
+                .await?
+        );
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        let mut rules = m.get_policy("p", "p");
+        rules.sort();
+        assert_eq!(vec![alice, alice_write, carol, dave], rules);
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_batch_boundaries() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Boundaries";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?;
+        let retry = RetryPolicy::default();
+        let writer = BatchWriter::new(&client, table_name, &retry, 25);
+
+        for size in [0, 1, 24, 25, 26, 50, 51, 1000] {
+            let rules: Vec<Vec<String>> = (0..size)
+                .map(|i| to_owned(vec![&format!("user{}", i), "data1", "read"]))
+                .collect();
+
+            // Written in pages of 25, the last one partial unless the size is a multiple of 25.
+            let entries = rules
+                .iter()
+                .map(|rule| {
+                    let item = vec![
+                        ("id", AttributeValue::S(LegacyMd5.item_id("p", rule))),
+                        ("pType", AttributeValue::S("p".to_string())),
+                        ("v0", AttributeValue::S(rule[0].clone())),
+                        ("v1", AttributeValue::S(rule[1].clone())),
+                        ("v2", AttributeValue::S(rule[2].clone())),
+                    ]
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value))
+                    .collect();
+                    BatchEntry::put("p", rule, item)
+                })
+                .collect();
+            writer.write(entries).await?;
+
+            let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+            adapter.load_policy(&mut m).await?;
+            assert_eq!(size, m.get_policy("p", "p").len());
+
+            // Removed through the batch writer too.
+            assert_eq!(size > 0, adapter.remove_policies("p", "p", rules).await?);
+
+            let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+            adapter.load_policy(&mut m).await?;
+            assert!(m.get_policy("p", "p").is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_error_kinds() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let mut missing = DynamoDBAdapter::new(&client, "Casbin_Policies_Missing")?;
+        let e = missing
+            .add_policy("p", "p", to_owned(vec!["alice", "data1", "read"]))
+            .await
+            .unwrap_err();
+        match DynamoDBAdapterError::from_casbin(&e) {
+            Some(DynamoDBAdapterError::ResourceNotFound(failed)) => {
+                assert_eq!("Casbin_Policies_Missing", failed.table_name)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let table_name = "Casbin_Policies_Errors";
+        init_table(&client, table_name).await;
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?.with_max_fields(2)?;
+        let e = adapter
+            .add_policy("p", "p", to_owned(vec!["alice", "data1", "read"]))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&e),
+            Some(DynamoDBAdapterError::RuleTooLong(_))
+        ));
+
+        client
+            .put_item()
+            .table_name(table_name)
+            .item("id", AttributeValue::S("broken".to_string()))
+            .item("pType", AttributeValue::S("p".to_string()))
+            .send()
+            .await
+            .unwrap();
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        let e = adapter.load_policy(&mut m).await.unwrap_err();
+        match DynamoDBAdapterError::from_casbin(&e) {
+            Some(DynamoDBAdapterError::MalformedItem(item)) => assert_eq!("broken", item.item_id),
+            other => panic!("unexpected error {:?}", other),
+        }
+        let streamed: Result<Vec<_>> = adapter.stream_policies().try_collect().await;
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&streamed.unwrap_err()),
+            Some(DynamoDBAdapterError::MalformedItem(_))
+        ));
+
+        // A field count below the stored fields, or too large to be a rule.
+        for (subject, count) in [("short", "2"), ("huge", "4000000000")] {
+            client
+                .put_item()
+                .table_name(table_name)
+                .item("id", AttributeValue::S("broken".to_string()))
+                .item("pType", AttributeValue::S("p".to_string()))
+                .item("v0", AttributeValue::S(subject.to_string()))
+                .item("v2", AttributeValue::S("read".to_string()))
+                .item("fieldCount", AttributeValue::N(count.to_string()))
+                .send()
+                .await
+                .unwrap();
+
+            let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+            let e = adapter.load_policy(&mut m).await.unwrap_err();
+            assert!(matches!(
+                DynamoDBAdapterError::from_casbin(&e),
+                Some(DynamoDBAdapterError::MalformedItem(_))
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_ptype_filters() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Ptype_Filters";
+        init_table(&client, table_name).await;
+
+        let model = r#"
+[request_definition]
+r = sub, dom, obj, act
+
+[policy_definition]
+p = sub, dom, obj, act
+
+[role_definition]
+g = _, _, _
+g2 = _, _
+
+[policy_effect]
+e = some(where (p.eft == allow))
+
+[matchers]
+m = g(r.sub, p.sub, r.dom) && g2(r.obj, p.obj) && r.dom == p.dom && r.act == p.act
+"#;
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?;
+        adapter
+            .add_policies(
+                "p",
+                "p",
+                vec![
+                    to_owned(vec!["admin", "domain1", "group1", "read"]),
+                    to_owned(vec!["admin", "domain2", "group2", "read"]),
+                ],
+            )
+            .await?;
+        adapter
+            .add_policies(
+                "g",
+                "g",
+                vec![
+                    to_owned(vec!["alice", "admin", "domain1"]),
+                    to_owned(vec!["bob", "admin", "domain2"]),
+                ],
+            )
+            .await?;
+        adapter
+            .add_policies(
+                "g",
+                "g2",
+                vec![
+                    to_owned(vec!["data1", "group1"]),
+                    to_owned(vec!["data2", "group2"]),
+                ],
+            )
+            .await?;
+
+        // The `g` filter has three values, more than `g2` rules have fields.
+        let mut m = DefaultModel::from_str(model).await?;
+        adapter
+            .load_filtered_policy(
+                &mut m,
+                Filter {
+                    p: vec!["", "domain1"],
+                    g: vec!["", "", "domain1"],
+                },
+            )
+            .await?;
+        assert!(adapter.is_filtered());
+        assert_eq!(1, m.get_policy("p", "p").len());
+        assert_eq!(1, m.get_policy("g", "g").len());
+        assert_eq!(2, m.get_policy("g", "g2").len());
+
+        let filter = PolicyFilter::new()
+            .ptype("g", &["", "", "domain1"])
+            .ptype("g2", &["data1"]);
+        let mut m = DefaultModel::from_str(model).await?;
+        adapter
+            .load_filtered_policy_by_ptype(&mut m, &filter)
+            .await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+        assert_eq!(
+            vec![to_owned(vec!["alice", "admin", "domain1"])],
+            m.get_policy("g", "g")
+        );
+        assert_eq!(
+            vec![to_owned(vec!["data1", "group1"])],
+            m.get_policy("g", "g2")
+        );
+
+        let filter = PolicyFilter::new().ptype("g2", &["data1", "group1", "extra"]);
+        let mut m = DefaultModel::from_str(model).await?;
+        adapter
+            .load_filtered_policy_by_ptype(&mut m, &filter)
+            .await?;
+        assert!(m.get_policy("g", "g2").is_empty());
+        assert_eq!(2, m.get_policy("g", "g").len());
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_field_predicates() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Predicates";
+        client
+            .delete_table()
+            .table_name(table_name)
+            .send()
+            .await
+            .ok();
+
+        let mut adapter = DynamoDBAdapter::new(&client, table_name)?.with_index(1, "v1-index")?;
+        adapter
+            .create_table_if_not_exists(&TableOptions {
+                billing: Billing::OnDemand,
+                ttl_attribute: None,
+                stream: false,
+            })
+            .await?;
+
+        adapter
+            .add_policies(
+                "p",
+                "p",
+                vec![
+                    to_owned(vec!["admin", "tenant-42/a", "data1", "read"]),
+                    to_owned(vec!["admin", "tenant-42/b", "data2", "read"]),
+                    to_owned(vec!["admin", "tenant-7", "data3", "write"]),
+                ],
+            )
+            .await?;
+        adapter
+            .add_policies(
+                "g",
+                "g",
+                vec![
+                    to_owned(vec!["alice", "admin", "tenant-42/a"]),
+                    to_owned(vec!["bob", "admin", "tenant-7"]),
+                    to_owned(vec!["carol", "admin", "tenant-42/b"]),
+                ],
+            )
+            .await?;
+
+        let load = |filter: PolicyFilter| {
+            let mut adapter = adapter.clone();
+            async move {
+                let mut m =
+                    DefaultModel::from_file("examples/rbac_with_domains_model.conf").await?;
+                adapter
+                    .load_filtered_policy_by_ptype(&mut m, &filter)
+                    .await?;
+                let mut p = m.get_policy("p", "p");
+                let mut g = m.get_policy("g", "g");
+                p.sort();
+                g.sort();
+                Ok::<_, casbin::Error>((p, g))
+            }
+        };
+
+        let (p, g) = load(PolicyFilter::new().field(
+            "p",
+            1,
+            FieldMatch::BeginsWith("tenant-42/".to_string()),
+        ))
+        .await?;
+        assert_eq!(2, p.len());
+        assert_eq!(3, g.len());
+
+        let (_, g) = load(PolicyFilter::new().field(
+            "g",
+            0,
+            FieldMatch::In(to_owned(vec!["alice", "bob", "dave"])),
+        ))
+        .await?;
+        assert_eq!(
+            vec![
+                to_owned(vec!["alice", "admin", "tenant-42/a"]),
+                to_owned(vec!["bob", "admin", "tenant-7"]),
+            ],
+            g
+        );
+
+        // Queries `v1-index` once per value.
+        let (p, _) = load(
+            PolicyFilter::new()
+                .field(
+                    "p",
+                    1,
+                    FieldMatch::In(to_owned(vec!["tenant-7", "tenant-42/b"])),
+                )
+                .field("p", 3, FieldMatch::NotEquals("write".to_string())),
+        )
+        .await?;
+        assert_eq!(
+            vec![to_owned(vec!["admin", "tenant-42/b", "data2", "read"])],
+            p
+        );
+
+        let (p, g) = load(PolicyFilter::new().field("g", 2, FieldMatch::In(Vec::new()))).await?;
+        assert_eq!(3, p.len());
+        assert!(g.is_empty());
+
+        // No rule has a fifth field.
+        let (p, _) =
+            load(PolicyFilter::new().field("p", 4, FieldMatch::NotEquals("read".to_string())))
+                .await?;
+        assert!(p.is_empty());
+
+        #[cfg(feature = "glob")]
+        {
+            let (p, g) = load(
+                PolicyFilter::new()
+                    .field("p", 1, FieldMatch::glob("tenant-*/a")?)
+                    .field("g", 2, FieldMatch::glob("tenant-?")?),
+            )
+            .await?;
+            assert_eq!(
+                vec![to_owned(vec!["admin", "tenant-42/a", "data1", "read"])],
+                p
+            );
+            assert_eq!(vec![to_owned(vec!["bob", "admin", "tenant-7"])], g);
+
+            let e = FieldMatch::glob("tenant-[").unwrap_err();
+            assert!(matches!(
+                DynamoDBAdapterError::from_casbin(&e),
+                Some(DynamoDBAdapterError::InvalidFilter(_))
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_audit_trail() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Audited";
+        let audit_table = "Casbin_Policies_Audit";
+        init_table(&client, table_name).await;
+        client
+            .delete_table()
+            .table_name(audit_table)
+            .send()
+            .await
+            .ok();
+
+        let mut adapter = DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .audit_table(audit_table)
+            .scan_segments(3)
+            .build()?;
+        adapter
+            .create_audit_table_if_not_exists(Billing::OnDemand)
+            .await?;
+        // The table now exists and matches.
+        adapter
+            .create_audit_table_if_not_exists(Billing::OnDemand)
+            .await?;
+
+        let alice = to_owned(vec!["alice", "data1", "read"]);
+        let admin = AuditContext::new()
+            .actor("admin@example.com")
+            .correlation_id("req-1");
+        assert!(
+            admin
+                .clone()
+                .scope(adapter.add_policy("p", "p", alice.clone()))
+                .await?
+        );
+        assert!(
+            !admin
+                .scope(adapter.add_policy("p", "p", alice.clone()))
+                .await?
+        );
+
+        adapter
+            .add_policies(
+                "p",
+                "p",
+                vec![
+                    to_owned(vec!["bob", "data2", "read"]),
+                    to_owned(vec!["bob", "data2", "write"]),
+                ],
+            )
+            .await?;
+
+        AuditContext::new()
+            .actor("auditor@example.com")
+            .scope(adapter.remove_policy("p", "p", alice.clone()))
+            .await?;
+
+        let history = adapter.audit_history_for_rule("p", &alice).await?;
+        assert_eq!(2, history.len());
+        assert_eq!(AuditOperation::Add, history[0].operation);
+        assert_eq!("add_policy", history[0].method);
+        assert_eq!(alice, history[0].rule);
+        assert_eq!(Some("admin@example.com".to_string()), history[0].actor);
+        assert_eq!("req-1", history[0].correlation_id);
+        assert_eq!(AuditOperation::Remove, history[1].operation);
+        assert_eq!(Some("auditor@example.com".to_string()), history[1].actor);
+        assert_ne!("req-1", history[1].correlation_id);
+        assert!(history[0].timestamp <= history[1].timestamp);
+
+        let history = adapter.audit_history_for_subject("bob").await?;
+        assert_eq!(2, history.len());
+        assert!(history
+            .iter()
+            .all(|e| e.method == "add_policies" && e.actor.is_none()));
+        assert_eq!(history[0].correlation_id, history[1].correlation_id);
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        m.add_policy("p", "p", to_owned(vec!["bob", "data2", "read"]));
+        m.add_policy("p", "p", to_owned(vec!["carol", "data3", "read"]));
+        adapter.save_policy(&mut m).await?;
+
+        let history = adapter.audit_history_for_subject("bob").await?;
+        assert_eq!(3, history.len());
+        assert_eq!(AuditOperation::Remove, history[2].operation);
+        assert_eq!(to_owned(vec!["bob", "data2", "write"]), history[2].rule);
+        assert_eq!("save_policy", history[2].method);
+
+        adapter.clear_policy().await?;
+        let history = adapter.audit_history_for_subject("carol").await?;
+        assert_eq!(
+            vec![
+                (AuditOperation::Add, "save_policy"),
+                (AuditOperation::Remove, "clear_policy")
+            ],
+            history
+                .iter()
+                .map(|e| (e.operation, e.method.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        // The events of a call share its correlation id, across the pages of every segment.
+        let dave_rules: Vec<Vec<String>> = (0..10)
+            .map(|i| to_owned(vec!["dave", &format!("data{}", i), "read"]))
+            .collect();
+        adapter.add_policies("p", "p", dave_rules).await?;
+        adapter.clear_policy().await?;
+        let mut cleared: Vec<_> = adapter
+            .audit_history_for_subject("dave")
+            .await?
+            .into_iter()
+            .filter(|e| e.method == "clear_policy")
+            .map(|e| e.correlation_id)
+            .collect();
+        assert_eq!(10, cleared.len());
+        cleared.dedup();
+        assert_eq!(1, cleared.len());
+
+        // Only the rules that were stored are recorded as removed.
+        let erin = to_owned(vec!["erin", "data1", "read"]);
+        adapter.add_policy("p", "p", erin.clone()).await?;
+        adapter
+            .remove_policies(
+                "p",
+                "p",
+                vec![erin.clone(), to_owned(vec!["erin", "data2", "read"])],
+            )
+            .await?;
+        let history = adapter.audit_history_for_subject("erin").await?;
+        assert_eq!(
+            vec![
+                (AuditOperation::Add, erin.clone()),
+                (AuditOperation::Remove, erin.clone())
+            ],
+            history
+                .into_iter()
+                .map(|e| (e.operation, e.rule))
+                .collect::<Vec<_>>()
+        );
+
+        // A failed audit write keeps the change and its event, until it is written again.
+        client
+            .delete_table()
+            .table_name(audit_table)
+            .send()
+            .await
+            .unwrap();
+        let frank = to_owned(vec!["frank", "data1", "read"]);
+        assert!(adapter.add_policy("p", "p", frank.clone()).await?);
+        let unrecorded = adapter.unrecorded_audit_events()?;
+        assert_eq!(1, unrecorded.len());
+        assert_eq!(frank, unrecorded[0].rule);
+        assert!(adapter.flush_audit_events().await.is_err());
+        assert_eq!(unrecorded, adapter.unrecorded_audit_events()?);
+
+        adapter
+            .create_audit_table_if_not_exists(Billing::OnDemand)
+            .await?;
+        adapter.flush_audit_events().await?;
+        assert!(adapter.unrecorded_audit_events()?.is_empty());
+        assert_eq!(
+            unrecorded,
+            adapter.audit_history_for_rule("p", &frank).await?
+        );
+
+        // A call fails, although its change is stored, once its events do not fit in the
+        // buffer, or whenever audit writes are required.
+        client
+            .delete_table()
+            .table_name(audit_table)
+            .send()
+            .await
+            .unwrap();
+        let grace = to_owned(vec!["grace", "data1", "read"]);
+        let mut capped = adapter.clone().with_max_unrecorded_audit_events(0);
+        let e = capped
+            .add_policy("p", "p", grace.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&e),
+            Some(DynamoDBAdapterError::AuditWrite(AuditWriteFailed {
+                events: 1,
+                kept: false,
+                ..
+            }))
+        ));
+        assert!(capped.unrecorded_audit_events()?.is_empty());
+
+        let heidi = to_owned(vec!["heidi", "data1", "read"]);
+        let mut required = adapter.clone().with_audit_required(true);
+        let e = required
+            .add_policy("p", "p", heidi.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&e),
+            Some(DynamoDBAdapterError::AuditWrite(AuditWriteFailed {
+                kept: true,
+                ..
+            }))
+        ));
+        assert_eq!(1, required.unrecorded_audit_events()?.len());
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert!(m.has_policy("p", "p", grace) && m.has_policy("p", "p", heidi));
+        adapter
+            .create_audit_table_if_not_exists(Billing::OnDemand)
+            .await?;
+        adapter.flush_audit_events().await?;
+
+        // The key schema of an audit table without its subject index.
+        let unindexed_table = "Casbin_Policies_Audit_Unindexed";
+        client
+            .delete_table()
+            .table_name(unindexed_table)
+            .send()
+            .await
+            .ok();
+        let mut req = client
+            .create_table()
+            .table_name(unindexed_table)
+            .billing_mode(BillingMode::PayPerRequest);
+        for (name, key_type) in [(RULE_KEY, KeyType::Hash), (EVENT_KEY, KeyType::Range)] {
+            req = req
+                .attribute_definitions(
+                    AttributeDefinition::builder()
+                        .attribute_name(name)
+                        .attribute_type(ScalarAttributeType::S)
+                        .build(),
+                )
+                .key_schema(
+                    KeySchemaElement::builder()
+                        .attribute_name(name)
+                        .key_type(key_type)
+                        .build(),
+                );
+        }
+        req.send().await.unwrap();
+
+        let adapter =
+            DynamoDBAdapter::new(&client, table_name)?.with_audit_table(unindexed_table)?;
+        let e = adapter
+            .create_audit_table_if_not_exists(Billing::OnDemand)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            DynamoDBAdapterError::from_casbin(&e),
+            Some(DynamoDBAdapterError::TableSetup(_))
+        ));
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "runtime-async-std", async_std::test)]
+    #[cfg_attr(feature = "runtime-tokio", tokio::test)]
+    async fn test_time_bounded_rules() -> std::result::Result<(), casbin::Error> {
+        use casbin::prelude::*;
+        use std::time::SystemTime;
+
+        let client = local_client().await;
+
+        let table_name = "Casbin_Policies_Time_Bounded";
+        init_table(&client, table_name).await;
+
+        // Rule fields and the attributes of the generation item can not expire items.
+        for attribute in ["v0", "pending", "pendingSince", "claimed"] {
+            assert!(DynamoDBAdapter::builder()
+                .client(&client)
+                .table_name(table_name)
+                .ttl_attribute(attribute)
+                .build()
+                .is_err());
+        }
+
+        let mut adapter = DynamoDBAdapter::builder()
+            .client(&client)
+            .table_name(table_name)
+            .ttl_attribute("expiresAt")
+            .build()?;
+
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        let alice = to_owned(vec!["alice", "data1", "read"]);
+        let bob = to_owned(vec!["bob", "data2", "read"]);
+        let carol = to_owned(vec!["carol", "data3", "read"]);
+
+        assert!(
+            adapter
+                .add_policy_with_expiry("p", alice.clone(), now + hour)
+                .await?
+        );
+        assert!(
+            !adapter
+                .add_policy_with_expiry("p", alice.clone(), now + hour)
+                .await?
+        );
+        assert!(
+            adapter
+                .add_policy_with_validity(
+                    "p",
+                    bob.clone(),
+                    Validity::between(now + hour, now + hour * 2)
+                )
+                .await?
+        );
+        assert!(
+            adapter
+                .add_policy_with_expiry("p", carol.clone(), now - Duration::from_secs(10))
+                .await?
+        );
+        assert!(adapter
+            .add_policy_with_validity("p", carol.clone(), Validity::between(now + hour, now))
+            .await
+            .is_err());
+
+        let item = client
+            .get_item()
+            .table_name(table_name)
+            .key("id", AttributeValue::S(LegacyMd5.item_id("p", &alice)))
+            .send()
+            .await
+            .unwrap();
+        let expires_at = item.item().and_then(|i| i.get("expiresAt")).cloned();
+        assert_eq!(
+            item.item().and_then(|i| i.get("validUntil")).cloned(),
+            expires_at
+        );
+        assert!(expires_at.is_some());
+
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(vec![alice.clone()], m.get_policy("p", "p"));
+
+        let next = adapter.next_validity_change().await?.unwrap();
+        let expected = now + hour;
+        assert!(
+            next <= expected && expected.duration_since(next).unwrap() < Duration::from_secs(1)
+        );
+
+        // An expired rule does not block granting it again.
+        assert!(
+            adapter
+                .add_policy_with_expiry("p", carol.clone(), now + hour)
+                .await?
+        );
+
+        // Saving keeps the rule that is not active yet.
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(2, m.get_policy("p", "p").len());
+        m.remove_policy("p", "p", carol.clone());
+        adapter.save_policy(&mut m).await?;
+
+        let stored: Vec<Vec<String>> = adapter
+            .stream_policies()
+            .map_ok(|(_, rule)| rule)
+            .try_collect()
+            .await?;
+        assert_eq!(vec![alice.clone()], stored);
+        let item = client
+            .get_item()
+            .table_name(table_name)
+            .key("id", AttributeValue::S(LegacyMd5.item_id("p", &bob)))
+            .send()
+            .await
+            .unwrap();
+        assert!(item.item().and_then(|i| i.get("validFrom")).is_some());
+
+        // A rule loaded before it expired stays expired when the model is saved, whether in a
+        // single transaction or as a snapshot.
+        let dave = to_owned(vec!["dave", "data4", "read"]);
+        let erin = to_owned(vec!["erin", "data5", "read"]);
+        let frank = to_owned(vec!["frank", "data6", "read"]);
+        assert!(
+            adapter
+                .add_policy_with_expiry(
+                    "p",
+                    dave.clone(),
+                    SystemTime::now() + Duration::from_secs(3)
+                )
+                .await?
+        );
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert!(m.has_policy("p", "p", dave.clone()));
+        sleep(Duration::from_secs(4)).await;
+
+        let report = adapter.sync_policy(&mut m).await?;
+        assert_eq!(
+            SyncReport {
+                added: 0,
+                removed: 0,
+                unchanged: 2
+            },
+            report
+        );
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        assert_eq!(vec![alice.clone()], m.get_policy("p", "p"));
+        // Nor is it stored for a removal.
+        assert!(!adapter.remove_policy("", "p", dave.clone()).await?);
+
+        let mut adapter = adapter.with_max_transaction_items(1)?;
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        for rule in [&alice, &dave, &erin, &frank] {
+            m.add_policy("p", "p", rule.clone());
+        }
+        let report = adapter.sync_policy(&mut m).await?;
+        assert_eq!(
+            SyncReport {
+                added: 2,
+                removed: 0,
+                unchanged: 2
+            },
+            report
+        );
+        let mut m = DefaultModel::from_file("examples/rbac_model.conf").await?;
+        adapter.load_policy(&mut m).await?;
+        let mut loaded = m.get_policy("p", "p");
+        loaded.sort();
+        assert_eq!(vec![alice, erin, frank], loaded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_query_plan() {
+        use crate::filter::{plan_reads, SectionFilter};
+        use crate::table::AttributeNames;
+        use std::collections::HashMap;
+
+        let mut indexes = HashMap::new();
+        indexes.insert(1, "v1-index".to_string());
+        let sections = vec![SectionFilter::new("p", 0, &["alice", "domain1"])];
+
+        let reads = plan_reads(
+            &sections,
+            &indexes,
+            TableLayout::Id,
+            &AttributeNames::default(),
+        );
+        assert_eq!(1, reads.len());
+        let read = reads.into_iter().next().unwrap();
+        assert_eq!(Some("v1-index".to_string()), read.index_name);
+
+        // The policy type is the index's sort key, so it narrows the query itself.
+        assert_eq!(
+            Some("#n0 = :v0 AND #n1 = :v1".to_string()),
+            read.key_condition
+        );
+        assert_eq!(Some("#n2 = :v2".to_string()), read.filter);
+        let (names, values) = read.expression.into_parts();
+        let (names, values) = (names.unwrap(), values.unwrap());
+        assert_eq!("v1", names["#n0"]);
+        assert_eq!("pType", names["#n1"]);
+        assert_eq!("v0", names["#n2"]);
+        assert_eq!(AttributeValue::S("domain1".to_string()), values[":v0"]);
+        assert_eq!(AttributeValue::S("p".to_string()), values[":v1"]);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let retry = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(200),
+        };
+
+        for attempt in 0..40 {
+            assert!(retry.backoff(attempt) <= Duration::from_millis(200));
+        }
+        assert!(retry.backoff(1) <= Duration::from_millis(20));
+    }
 }
@@ -0,0 +1,431 @@
+use std::{collections::HashMap, time::Duration};
+
+use aws_sdk_dynamodb::{
+    client::fluent_builders::CreateTable,
+    error::DescribeTableErrorKind,
+    model::{
+        AttributeDefinition, BillingMode, GlobalSecondaryIndex, IndexStatus, KeySchemaElement,
+        KeyType, Projection, ProjectionType, ProvisionedThroughput, ScalarAttributeType,
+        StreamSpecification, StreamViewType, TableDescription, TableStatus,
+        TimeToLiveSpecification, TimeToLiveStatus,
+    },
+    types::SdkError,
+    Client,
+};
+use casbin::Result;
+
+use crate::batch::sleep;
+use crate::{DynamoDBAdapterError, TableSetupFailed};
+
+/// Number of `DescribeTable` polls made while waiting for a table to become `ACTIVE`.
+const MAX_WAIT_ATTEMPTS: u32 = 120;
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Key schema of the policy table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableLayout {
+    /// A single `id` partition key holding the rule hash. Every read is a `Scan`.
+    #[default]
+    Id,
+    /// `pType` partition key with the rule hash as `id` sort key.
+    ///
+    /// Loads and removals only read the partitions of the policy types they need with `Query`.
+    PType,
+}
+
+impl TableLayout {
+    fn key_schema<'a>(&self, attributes: &'a AttributeNames) -> Vec<(&'a str, KeyType)> {
+        match self {
+            TableLayout::Id => vec![(&attributes.id, KeyType::Hash)],
+            TableLayout::PType => vec![
+                (&attributes.ptype, KeyType::Hash),
+                (&attributes.id, KeyType::Range),
+            ],
+        }
+    }
+}
+
+/// Names of the attributes holding the item id, the policy type and the rule fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AttributeNames {
+    pub id: String,
+    pub ptype: String,
+    /// Rule field `i` is stored in the attribute `{field_prefix}{i}`.
+    pub field_prefix: String,
+}
+
+impl Default for AttributeNames {
+    fn default() -> Self {
+        Self {
+            id: "id".to_string(),
+            ptype: "pType".to_string(),
+            field_prefix: "v".to_string(),
+        }
+    }
+}
+
+impl AttributeNames {
+    pub fn field(&self, index: usize) -> String {
+        format!("{}{}", self.field_prefix, index)
+    }
+
+    /// Position of the rule field stored in `attribute`, if it is one.
+    pub fn field_index(&self, attribute: &str) -> Option<usize> {
+        let digits = attribute.strip_prefix(self.field_prefix.as_str())?;
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        digits.parse().ok()
+    }
+}
+
+/// Capacity mode of a table created by the adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Billing {
+    #[default]
+    OnDemand,
+    /// Provisioned capacity, applied to the table and each of its global secondary indexes.
+    Provisioned {
+        read_capacity: i64,
+        write_capacity: i64,
+    },
+}
+
+/// Settings used by `DynamoDBAdapter::create_table_if_not_exists`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableOptions {
+    pub billing: Billing,
+    /// Attribute enabled as DynamoDB time to live on the table, new or existing.
+    pub ttl_attribute: Option<String>,
+    /// Enables a stream with new and old images, as required by `DynamoDBWatcher`.
+    pub stream: bool,
+}
+
+pub(crate) async fn create_table_if_not_exists(
+    client: &Client,
+    table_name: &str,
+    layout: TableLayout,
+    attributes: &AttributeNames,
+    indexes: &HashMap<usize, String>,
+    options: &TableOptions,
+) -> Result<()> {
+    if describe_table(client, table_name).await?.is_none() {
+        create_table(client, table_name, layout, attributes, indexes, options).await?;
+    }
+
+    // A table created concurrently by another process may still be `CREATING`.
+    let table = wait_until_active(client, table_name).await?;
+    verify_table(table_name, &table, layout, attributes, indexes)?;
+
+    if let Some(attribute) = &options.ttl_attribute {
+        enable_time_to_live(client, table_name, attribute).await?;
+    }
+
+    Ok(())
+}
+
+/// Enables time to live on `attribute`, unless it already is, failing if the table has it
+/// enabled on another attribute.
+async fn enable_time_to_live(client: &Client, table_name: &str, attribute: &str) -> Result<()> {
+    let res = client
+        .describe_time_to_live()
+        .table_name(table_name)
+        .send()
+        .await
+        .map_err(|e| DynamoDBAdapterError::from_sdk(table_name, "DescribeTimeToLive", None, e))?;
+
+    let description = res.time_to_live_description();
+    let status = description.and_then(|d| d.time_to_live_status());
+    if matches!(
+        status,
+        Some(TimeToLiveStatus::Enabled) | Some(TimeToLiveStatus::Enabling)
+    ) {
+        let enabled = description.and_then(|d| d.attribute_name());
+        if enabled == Some(attribute) {
+            return Ok(());
+        }
+
+        return Err(TableSetupFailed {
+            table_name: table_name.to_string(),
+            reason: format!(
+                "time to live is enabled on {} instead of {}",
+                enabled.unwrap_or_default(),
+                attribute
+            ),
+        }
+        .into());
+    }
+
+    client
+        .update_time_to_live()
+        .table_name(table_name)
+        .time_to_live_specification(
+            TimeToLiveSpecification::builder()
+                .attribute_name(attribute)
+                .enabled(true)
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(|e| DynamoDBAdapterError::from_sdk(table_name, "UpdateTimeToLive", None, e))?;
+
+    Ok(())
+}
+
+pub(crate) async fn describe_table(
+    client: &Client,
+    table_name: &str,
+) -> Result<Option<TableDescription>> {
+    match client.describe_table().table_name(table_name).send().await {
+        Ok(res) => Ok(res.table().cloned()),
+        Err(SdkError::ServiceError { err, .. })
+            if matches!(
+                err.kind,
+                DescribeTableErrorKind::ResourceNotFoundException(_)
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(DynamoDBAdapterError::from_sdk(table_name, "DescribeTable", None, e).into()),
+    }
+}
+
+async fn create_table(
+    client: &Client,
+    table_name: &str,
+    layout: TableLayout,
+    attributes: &AttributeNames,
+    indexes: &HashMap<usize, String>,
+    options: &TableOptions,
+) -> Result<()> {
+    let throughput = provisioned_throughput(options.billing);
+
+    let mut definitions = vec![attributes.ptype.clone(), attributes.id.clone()];
+    if layout == TableLayout::Id && indexes.is_empty() {
+        definitions.retain(|a| a == &attributes.id);
+    }
+
+    let mut req = create_table_request(client, table_name, options.billing);
+
+    if options.stream {
+        req = req.stream_specification(
+            StreamSpecification::builder()
+                .stream_enabled(true)
+                .stream_view_type(StreamViewType::NewAndOldImages)
+                .build(),
+        );
+    }
+
+    for (name, key_type) in layout.key_schema(attributes) {
+        req = req.key_schema(key_element(name, key_type));
+    }
+
+    let mut fields: Vec<_> = indexes.iter().collect();
+    fields.sort();
+    for (field, index_name) in fields {
+        let attribute = attributes.field(*field);
+        req = req.global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(index_name)
+                .key_schema(key_element(&attribute, KeyType::Hash))
+                .key_schema(key_element(&attributes.ptype, KeyType::Range))
+                .projection(
+                    Projection::builder()
+                        .projection_type(ProjectionType::All)
+                        .build(),
+                )
+                .set_provisioned_throughput(throughput.clone())
+                .build(),
+        );
+        definitions.push(attribute);
+    }
+
+    for attribute in definitions {
+        req = req.attribute_definitions(string_attribute(&attribute));
+    }
+
+    send_create_table(table_name, req).await
+}
+
+/// `CreateTable` request with the capacity mode of `billing`, whose provisioned throughput
+/// also applies to the global secondary indexes, see `provisioned_throughput`.
+pub(crate) fn create_table_request(
+    client: &Client,
+    table_name: &str,
+    billing: Billing,
+) -> CreateTable {
+    client
+        .create_table()
+        .table_name(table_name)
+        .set_provisioned_throughput(provisioned_throughput(billing))
+        .billing_mode(match billing {
+            Billing::OnDemand => BillingMode::PayPerRequest,
+            Billing::Provisioned { .. } => BillingMode::Provisioned,
+        })
+}
+
+/// Throughput of a table or global secondary index, `None` when billed on demand.
+pub(crate) fn provisioned_throughput(billing: Billing) -> Option<ProvisionedThroughput> {
+    match billing {
+        Billing::OnDemand => None,
+        Billing::Provisioned {
+            read_capacity,
+            write_capacity,
+        } => Some(
+            ProvisionedThroughput::builder()
+                .read_capacity_units(read_capacity)
+                .write_capacity_units(write_capacity)
+                .build(),
+        ),
+    }
+}
+
+pub(crate) fn key_element(name: &str, key_type: KeyType) -> KeySchemaElement {
+    KeySchemaElement::builder()
+        .attribute_name(name)
+        .key_type(key_type)
+        .build()
+}
+
+pub(crate) fn string_attribute(name: &str) -> AttributeDefinition {
+    AttributeDefinition::builder()
+        .attribute_name(name)
+        .attribute_type(ScalarAttributeType::S)
+        .build()
+}
+
+pub(crate) async fn send_create_table(table_name: &str, req: CreateTable) -> Result<()> {
+    match req.send().await {
+        Ok(_) => Ok(()),
+        // Created concurrently by another process, the caller waits for it to become active.
+        Err(SdkError::ServiceError { err, .. }) if err.is_resource_in_use_exception() => Ok(()),
+        Err(e) => Err(DynamoDBAdapterError::from_sdk(table_name, "CreateTable", None, e).into()),
+    }
+}
+
+/// Waits until the table and its global secondary indexes are `ACTIVE`, returning its
+/// description.
+pub(crate) async fn wait_until_active(
+    client: &Client,
+    table_name: &str,
+) -> Result<TableDescription> {
+    for _ in 0..MAX_WAIT_ATTEMPTS {
+        if let Some(table) = describe_table(client, table_name).await? {
+            let indexes_active = table
+                .global_secondary_indexes()
+                .unwrap_or_default()
+                .iter()
+                .all(|i| i.index_status() == Some(&IndexStatus::Active));
+
+            if table.table_status() == Some(&TableStatus::Active) && indexes_active {
+                return Ok(table);
+            }
+        }
+
+        sleep(WAIT_INTERVAL).await;
+    }
+
+    Err(TableSetupFailed {
+        table_name: table_name.to_string(),
+        reason: "table did not become ACTIVE in time".to_string(),
+    }
+    .into())
+}
+
+fn verify_table(
+    table_name: &str,
+    table: &TableDescription,
+    layout: TableLayout,
+    attributes: &AttributeNames,
+    indexes: &HashMap<usize, String>,
+) -> Result<()> {
+    let mismatch = |reason: String| -> casbin::Error {
+        TableSetupFailed {
+            table_name: table_name.to_string(),
+            reason,
+        }
+        .into()
+    };
+
+    let found = describe_key_schema(table.key_schema().unwrap_or_default());
+    let expected = layout
+        .key_schema(attributes)
+        .into_iter()
+        .map(|(name, key_type)| format!("{} {}", name, key_type.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if found != expected {
+        return Err(mismatch(format!(
+            "key schema is [{}] but the {:?} layout expects [{}]",
+            found, layout, expected
+        )));
+    }
+
+    for (field, index_name) in indexes {
+        verify_index(
+            table_name,
+            table,
+            index_name,
+            &attributes.field(*field),
+            &attributes.ptype,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Checks that `table` has the global secondary index `index_name`, partitioned by `hash` and
+/// sorted by `range`.
+pub(crate) fn verify_index(
+    table_name: &str,
+    table: &TableDescription,
+    index_name: &str,
+    hash: &str,
+    range: &str,
+) -> Result<()> {
+    let mismatch = |reason: String| -> casbin::Error {
+        TableSetupFailed {
+            table_name: table_name.to_string(),
+            reason,
+        }
+        .into()
+    };
+
+    let gsi = table
+        .global_secondary_indexes()
+        .unwrap_or_default()
+        .iter()
+        .find(|i| i.index_name() == Some(index_name))
+        .ok_or_else(|| mismatch(format!("global secondary index {} is missing", index_name)))?;
+
+    let found = describe_key_schema(gsi.key_schema().unwrap_or_default());
+    let expected = format!("{} HASH, {} RANGE", hash, range);
+    if found != expected {
+        return Err(mismatch(format!(
+            "key schema of global secondary index {} is [{}] but the adapter expects [{}]",
+            index_name, found, expected
+        )));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn describe_key_schema(key_schema: &[KeySchemaElement]) -> String {
+    let mut elements: Vec<_> = key_schema.iter().collect();
+    // HASH sorts before RANGE.
+    elements.sort_by_key(|k| k.key_type().map(|t| t.as_str().to_string()));
+
+    elements
+        .iter()
+        .map(|k| {
+            format!(
+                "{} {}",
+                k.attribute_name().unwrap_or_default(),
+                k.key_type().map(|t| t.as_str()).unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
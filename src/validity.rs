@@ -0,0 +1,61 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Attribute holding the first second a rule is loaded.
+pub(crate) const VALID_FROM: &str = "validFrom";
+/// Attribute holding the second from which a rule is no longer loaded.
+pub(crate) const VALID_UNTIL: &str = "validUntil";
+
+/// Time window in which a stored rule is loaded, see `DynamoDBAdapter::add_policy_with_validity`.
+///
+/// Bounds are stored as Unix timestamps in seconds, like DynamoDB time to live attributes;
+/// `None` leaves that side open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Validity {
+    pub valid_from: Option<SystemTime>,
+    pub valid_until: Option<SystemTime>,
+}
+
+impl Validity {
+    /// Active from now until `valid_until`.
+    pub fn until(valid_until: SystemTime) -> Self {
+        Self {
+            valid_from: None,
+            valid_until: Some(valid_until),
+        }
+    }
+
+    /// Active from `valid_from` until `valid_until`.
+    pub fn between(valid_from: SystemTime, valid_until: SystemTime) -> Self {
+        Self {
+            valid_from: Some(valid_from),
+            valid_until: Some(valid_until),
+        }
+    }
+
+    pub(crate) fn valid_from_seconds(&self) -> Option<i64> {
+        self.valid_from.map(epoch_seconds)
+    }
+
+    pub(crate) fn valid_until_seconds(&self) -> Option<i64> {
+        self.valid_until.map(epoch_seconds)
+    }
+}
+
+pub(crate) fn epoch_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+pub(crate) fn from_epoch_seconds(seconds: i64) -> SystemTime {
+    if seconds >= 0 {
+        UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+    }
+}
+
+pub(crate) fn now_seconds() -> i64 {
+    epoch_seconds(SystemTime::now())
+}
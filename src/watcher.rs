@@ -0,0 +1,543 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use aws_sdk_dynamodb::model::AttributeValue;
+use aws_sdk_dynamodbstreams::{
+    error::GetRecordsErrorKind,
+    model::{AttributeValue as StreamAttributeValue, OperationType, Record, ShardIteratorType},
+    types::SdkError,
+    Client as StreamsClient,
+};
+use casbin::{EventData, Result, Watcher};
+use futures_util::lock::Mutex as AsyncMutex;
+
+use crate::adapter::DynamoDBAdapter;
+use crate::batch::sleep;
+use crate::validity;
+use crate::{DynamoDBAdapterError, TableSetupFailed};
+
+type UpdateCallback = Box<dyn FnMut() + Send + Sync>;
+type ChangeCallback = Box<dyn FnMut(&PolicyChange) + Send + Sync>;
+
+/// A rule change read from the table's stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyChange {
+    Added(String, Vec<String>),
+    Removed(String, Vec<String>),
+    /// The whole policy was replaced by `save_policy`, enforcers should reload it.
+    Reload,
+}
+
+#[derive(Debug, Clone)]
+struct ShardState {
+    parent_shard_id: Option<String>,
+    iterator: Option<String>,
+    /// Sequence number of the last record delivered, the checkpoint to resume from.
+    sequence_number: Option<String>,
+    /// Where the shard is reopened without a checkpoint: `Latest` for the shards open when the
+    /// watcher was created, so an expired iterator does not replay their history.
+    start: ShardIteratorType,
+    finished: bool,
+}
+
+impl Default for ShardState {
+    fn default() -> Self {
+        Self {
+            parent_shard_id: None,
+            iterator: None,
+            sequence_number: None,
+            start: ShardIteratorType::TrimHorizon,
+            finished: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    shards: HashMap<String, ShardState>,
+    initialized: bool,
+    /// Generation published by the last snapshot save seen, to tell which rules are visible.
+    generation: i64,
+}
+
+/// A casbin [`Watcher`] that follows the DynamoDB Stream of the policy table.
+///
+/// The stream must be enabled with `NEW_AND_OLD_IMAGES`. The watcher is cheap to clone:
+/// give one clone to the enforcer with `set_watcher` and drive another with [`run`](Self::run)
+/// or [`poll`](Self::poll). Shards open when the watcher is created are read from their latest
+/// record, shards created later by resharding are read from the start once their parent has
+/// been drained.
+#[derive(Clone)]
+pub struct DynamoDBWatcher {
+    adapter: DynamoDBAdapter,
+    client: StreamsClient,
+    stream_arn: String,
+    state: Arc<AsyncMutex<StreamState>>,
+    update_callback: Arc<Mutex<Option<UpdateCallback>>>,
+    change_callback: Arc<Mutex<Option<ChangeCallback>>>,
+}
+
+impl DynamoDBWatcher {
+    /// Creates a watcher for the stream of the adapter's table.
+    pub async fn new(adapter: &DynamoDBAdapter, client: &StreamsClient) -> Result<Self> {
+        let stream_arn = adapter
+            .stream_arn()
+            .await?
+            .ok_or_else(|| TableSetupFailed {
+                table_name: adapter.table_name().to_string(),
+                reason: "table has no stream enabled".to_string(),
+            })?;
+
+        let watcher = Self {
+            adapter: adapter.clone(),
+            client: client.clone(),
+            stream_arn,
+            state: Arc::new(AsyncMutex::new(StreamState {
+                generation: adapter.current_generation().await?,
+                ..StreamState::default()
+            })),
+            update_callback: Arc::new(Mutex::new(None)),
+            change_callback: Arc::new(Mutex::new(None)),
+        };
+
+        // Positions the open shards at their latest record, changes made from now on are seen.
+        {
+            let mut state = watcher.state.lock().await;
+            watcher.discover_shards(&mut state).await?;
+        }
+
+        Ok(watcher)
+    }
+
+    /// Sets a callback invoked with every rule change, to apply it incrementally.
+    pub fn set_change_callback(&mut self, cb: ChangeCallback) {
+        *lock(&self.change_callback) = Some(cb);
+    }
+
+    /// Sequence number of the last record delivered for each shard.
+    pub async fn checkpoints(&self) -> HashMap<String, String> {
+        let state = self.state.lock().await;
+
+        state
+            .shards
+            .iter()
+            .filter_map(|(id, shard)| Some((id.clone(), shard.sequence_number.clone()?)))
+            .collect()
+    }
+
+    /// Resumes reading the given shards after their checkpointed sequence numbers, for example
+    /// those saved from [`checkpoints`](Self::checkpoints) before a restart.
+    pub async fn restore_checkpoints(&self, checkpoints: HashMap<String, String>) {
+        let mut state = self.state.lock().await;
+
+        for (shard_id, sequence_number) in checkpoints {
+            let shard = state.shards.entry(shard_id).or_default();
+            shard.sequence_number = Some(sequence_number);
+            shard.iterator = None;
+        }
+    }
+
+    /// Polls the stream every `interval`, until an error occurs.
+    pub async fn run(&self, interval: Duration) -> Result<()> {
+        loop {
+            self.poll().await?;
+            sleep(interval).await;
+        }
+    }
+
+    /// Reads the records available in every shard and invokes the callbacks.
+    ///
+    /// The update callback set by the enforcer is invoked once if anything changed. Checkpoints
+    /// only move once the changes read were delivered: when reading a shard fails, the changes
+    /// read before are delivered and the shard is read again from its checkpoint by the next
+    /// poll. A record that can not be decoded is reported after the changes before it, and
+    /// skipped by the next poll.
+    pub async fn poll(&self) -> Result<Vec<PolicyChange>> {
+        let mut state = self.state.lock().await;
+
+        self.discover_shards(&mut state).await?;
+
+        let shard_ids: Vec<String> = state.shards.keys().cloned().collect();
+        let mut ready: Vec<String> = shard_ids
+            .iter()
+            .filter(|id| is_ready(&state, id))
+            .cloned()
+            .collect();
+        // Parents before children.
+        ready.sort_by_key(|id| depth(&state, id));
+
+        let mut changes = Vec::new();
+        let mut read = Vec::new();
+        let mut generation = state.generation;
+        let mut failure = None;
+        for shard_id in ready {
+            let shard = state.shards.get(&shard_id).cloned().unwrap_or_default();
+            let (records, mut shard) = match self.read_shard(shard, &shard_id).await {
+                Ok(read) => read,
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            };
+
+            for record in records {
+                match self.decode(&mut generation, &record) {
+                    Ok(change) => changes.extend(change),
+                    Err(e) => {
+                        // Resume after the record that failed.
+                        if let Some(sequence_number) =
+                            record.dynamodb().and_then(|r| r.sequence_number())
+                        {
+                            shard.sequence_number = Some(sequence_number.to_string());
+                            shard.iterator = None;
+                        }
+                        shard.finished = false;
+                        failure = Some(e);
+                        break;
+                    }
+                }
+            }
+
+            read.push((shard_id, shard));
+            if failure.is_some() {
+                break;
+            }
+        }
+
+        if !changes.is_empty() {
+            if let Some(cb) = lock(&self.change_callback).as_mut() {
+                for change in &changes {
+                    cb(change);
+                }
+            }
+            if let Some(cb) = lock(&self.update_callback).as_mut() {
+                cb();
+            }
+        }
+
+        state.shards.extend(read);
+        state.generation = generation;
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(changes),
+        }
+    }
+
+    async fn discover_shards(&self, state: &mut StreamState) -> Result<()> {
+        let mut exclusive_start_shard_id = None;
+
+        loop {
+            let res = self
+                .client
+                .describe_stream()
+                .stream_arn(&self.stream_arn)
+                .set_exclusive_start_shard_id(exclusive_start_shard_id)
+                .send()
+                .await
+                .map_err(|e| {
+                    DynamoDBAdapterError::from_sdk(
+                        self.adapter.table_name(),
+                        "DescribeStream",
+                        None,
+                        e,
+                    )
+                })?;
+
+            let description = match res.stream_description() {
+                Some(description) => description,
+                None => break,
+            };
+
+            for shard in description.shards().unwrap_or_default() {
+                let shard_id = match shard.shard_id() {
+                    Some(id) => id.to_string(),
+                    None => continue,
+                };
+                let closed = shard
+                    .sequence_number_range()
+                    .and_then(|r| r.ending_sequence_number())
+                    .is_some();
+
+                if let Some(known) = state.shards.get_mut(&shard_id) {
+                    // Restored from a checkpoint before being discovered.
+                    if known.parent_shard_id.is_none() {
+                        known.parent_shard_id = shard.parent_shard_id().map(str::to_string);
+                    }
+                    continue;
+                }
+
+                // History before the watcher started is not replayed.
+                let finished = !state.initialized && closed;
+                let start = if state.initialized {
+                    ShardIteratorType::TrimHorizon
+                } else {
+                    ShardIteratorType::Latest
+                };
+                let iterator = if state.initialized || finished {
+                    None
+                } else {
+                    Some(self.shard_iterator(&shard_id, start.clone(), None).await?)
+                };
+
+                state.shards.insert(
+                    shard_id,
+                    ShardState {
+                        parent_shard_id: shard.parent_shard_id().map(str::to_string),
+                        iterator,
+                        sequence_number: None,
+                        start,
+                        finished,
+                    },
+                );
+            }
+
+            exclusive_start_shard_id = description.last_evaluated_shard_id().map(str::to_string);
+            if exclusive_start_shard_id.is_none() {
+                break;
+            }
+        }
+
+        state.initialized = true;
+
+        Ok(())
+    }
+
+    async fn shard_iterator(
+        &self,
+        shard_id: &str,
+        iterator_type: ShardIteratorType,
+        sequence_number: Option<String>,
+    ) -> Result<String> {
+        let res = self
+            .client
+            .get_shard_iterator()
+            .stream_arn(&self.stream_arn)
+            .shard_id(shard_id)
+            .shard_iterator_type(iterator_type)
+            .set_sequence_number(sequence_number)
+            .send()
+            .await
+            .map_err(|e| {
+                DynamoDBAdapterError::from_sdk(
+                    self.adapter.table_name(),
+                    "GetShardIterator",
+                    None,
+                    e,
+                )
+            })?;
+
+        Ok(res.shard_iterator().unwrap_or_default().to_string())
+    }
+
+    /// Reads the available records of a shard, returning them with the shard's position after
+    /// them; the caller stores it once the records were delivered.
+    async fn read_shard(
+        &self,
+        mut shard: ShardState,
+        shard_id: &str,
+    ) -> Result<(Vec<Record>, ShardState)> {
+        let mut records = Vec::new();
+
+        loop {
+            let iterator = match (shard.iterator.clone(), shard.sequence_number.clone()) {
+                (Some(iterator), _) => iterator,
+                (None, Some(sequence_number)) => {
+                    self.shard_iterator(
+                        shard_id,
+                        ShardIteratorType::AfterSequenceNumber,
+                        Some(sequence_number),
+                    )
+                    .await?
+                }
+                (None, None) => {
+                    self.shard_iterator(shard_id, shard.start.clone(), None)
+                        .await?
+                }
+            };
+
+            let res = match self
+                .client
+                .get_records()
+                .shard_iterator(&iterator)
+                .send()
+                .await
+            {
+                Ok(res) => res,
+                Err(SdkError::ServiceError { err, .. })
+                    if matches!(err.kind, GetRecordsErrorKind::ExpiredIteratorException(_)) =>
+                {
+                    // Recreated from the checkpoint on the next iteration.
+                    shard.iterator = None;
+                    continue;
+                }
+                Err(e) => {
+                    return Err(DynamoDBAdapterError::from_sdk(
+                        self.adapter.table_name(),
+                        "GetRecords",
+                        None,
+                        e,
+                    )
+                    .into())
+                }
+            };
+
+            let page = res.records().unwrap_or_default().to_vec();
+
+            if let Some(sequence_number) = page
+                .last()
+                .and_then(|r| r.dynamodb())
+                .and_then(|r| r.sequence_number())
+            {
+                shard.sequence_number = Some(sequence_number.to_string());
+            }
+
+            match res.next_shard_iterator() {
+                Some(next) => shard.iterator = Some(next.to_string()),
+                None => {
+                    shard.iterator = None;
+                    shard.finished = true;
+                }
+            }
+
+            let drained = page.is_empty() || shard.finished;
+            records.extend(page);
+            if drained {
+                return Ok((records, shard));
+            }
+        }
+    }
+
+    /// Decodes a record into the change it makes to the loaded policy, given the `generation`
+    /// published before it, which the record of a flip advances.
+    fn decode(&self, generation: &mut i64, record: &Record) -> Result<Option<PolicyChange>> {
+        let data = match record.dynamodb() {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let old = data.old_image().map(convert_item);
+        let new = data.new_image().map(convert_item);
+
+        let item = match new.as_ref().or(old.as_ref()) {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        if !self.adapter.in_namespace(item) {
+            return Ok(None);
+        }
+
+        if self.adapter.is_generation_item(item) {
+            // Claims of snapshot saves also write the generation item, only a flip publishes.
+            let published = |item: &Option<HashMap<String, AttributeValue>>| -> Option<i64> {
+                item.as_ref()?.get("gen")?.as_n().ok()?.parse().ok()
+            };
+            if published(&new) == published(&old) {
+                return Ok(None);
+            }
+
+            *generation = published(&new).unwrap_or(0);
+            return Ok(Some(PolicyChange::Reload));
+        }
+
+        // Rules written or retired by a snapshot save are only visible once the generation
+        // item publishes them, which is reported as a reload.
+        let now = validity::now_seconds();
+        let generation = *generation;
+        let loaded = |item: &Option<HashMap<String, AttributeValue>>| {
+            item.as_ref()
+                .is_some_and(|item| self.adapter.is_loaded(item, generation, now))
+        };
+
+        Ok(match record.event_name() {
+            // Also a rule stored again over one that was not loaded, e.g. because it expired.
+            Some(OperationType::Insert) | Some(OperationType::Modify)
+                if loaded(&new) && !loaded(&old) =>
+            {
+                let (ptype, rule) = self.adapter.item_to_policy(item)?;
+                Some(PolicyChange::Added(ptype, rule))
+            }
+            Some(OperationType::Remove)
+                if old
+                    .as_ref()
+                    .is_some_and(|item| self.adapter.is_visible(item, generation)) =>
+            {
+                let (ptype, rule) = self.adapter.item_to_policy(item)?;
+                Some(PolicyChange::Removed(ptype, rule))
+            }
+            _ => None,
+        })
+    }
+}
+
+impl Watcher for DynamoDBWatcher {
+    fn set_update_callback(&mut self, cb: Box<dyn FnMut() + Send + Sync>) {
+        *lock(&self.update_callback) = Some(cb);
+    }
+
+    /// Writes made through the adapter reach the other enforcers through the stream.
+    fn update(&mut self, _d: EventData) {}
+}
+
+/// Locks a callback, even if an earlier invocation panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn is_ready(state: &StreamState, shard_id: &str) -> bool {
+    let shard = match state.shards.get(shard_id) {
+        Some(shard) => shard,
+        None => return false,
+    };
+
+    !shard.finished
+        && shard
+            .parent_shard_id
+            .as_ref()
+            .and_then(|parent| state.shards.get(parent))
+            .is_none_or(|parent| parent.finished)
+}
+
+fn depth(state: &StreamState, shard_id: &str) -> usize {
+    let mut depth = 0;
+    let mut seen = HashSet::new();
+    let mut current = shard_id;
+
+    while let Some(parent) = state
+        .shards
+        .get(current)
+        .and_then(|s| s.parent_shard_id.as_deref())
+    {
+        if !seen.insert(parent) {
+            break;
+        }
+        depth += 1;
+        current = parent;
+    }
+
+    depth
+}
+
+fn convert_item(image: &HashMap<String, StreamAttributeValue>) -> HashMap<String, AttributeValue> {
+    image.iter().map(|(k, v)| (k.clone(), convert(v))).collect()
+}
+
+fn convert(value: &StreamAttributeValue) -> AttributeValue {
+    match value {
+        StreamAttributeValue::S(v) => AttributeValue::S(v.clone()),
+        StreamAttributeValue::N(v) => AttributeValue::N(v.clone()),
+        StreamAttributeValue::B(v) => AttributeValue::B(v.clone()),
+        StreamAttributeValue::Ss(v) => AttributeValue::Ss(v.clone()),
+        StreamAttributeValue::Ns(v) => AttributeValue::Ns(v.clone()),
+        StreamAttributeValue::Bs(v) => AttributeValue::Bs(v.clone()),
+        StreamAttributeValue::Bool(v) => AttributeValue::Bool(*v),
+        StreamAttributeValue::Null(v) => AttributeValue::Null(*v),
+        StreamAttributeValue::L(v) => AttributeValue::L(v.iter().map(convert).collect()),
+        StreamAttributeValue::M(v) => AttributeValue::M(convert_item(v)),
+        _ => AttributeValue::Null(true),
+    }
+}